@@ -0,0 +1,22 @@
+use std::env;
+
+/// `alloc-track` walks the frame pointers to record call sites, the other
+/// configurations let the compiler use rbp as a general register.
+fn main() {
+    println!("cargo:rerun-if-env-changed=RUSTFLAGS");
+    println!("cargo:rerun-if-env-changed=CARGO_ENCODED_RUSTFLAGS");
+    if env::var_os("CARGO_FEATURE_ALLOC_TRACK").is_none() {
+        return;
+    }
+
+    let flags = env::var("CARGO_ENCODED_RUSTFLAGS")
+        .map(|flags| flags.replace('\x1f', " "))
+        .or_else(|_| env::var("RUSTFLAGS"))
+        .unwrap_or_default();
+    let with_frame_pointers = flags.contains("force-frame-pointers=yes")
+        || flags.contains("force-frame-pointers=on")
+        || flags.contains("force-frame-pointers=true");
+    if !with_frame_pointers {
+        panic!("alloc-track needs frame pointers, build with RUSTFLAGS=\"-C force-frame-pointers=yes\"");
+    }
+}
@@ -0,0 +1,74 @@
+use crate::acpi::sdt::{GenericAddress, Sdt};
+
+/// The Fixed ACPI Description Table, power management registers.
+///
+/// Port fields are 0 when the block does not exist.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    /// Physical address of the DSDT
+    pub dsdt: u64,
+    /// ISA interrupt of the ACPI system control interrupt
+    pub sci_interrupt: u16,
+    /// Port to write `acpi_enable` or `acpi_disable` to switch modes
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub pm_timer_length: u8,
+    /// RTC register holding the century, 0 if there is none
+    pub century: u8,
+    /// IA-PC boot architecture flags, revision 2 and later
+    pub boot_architecture: u16,
+    pub flags: u32,
+    /// Register to write `reset_value` to to reset the machine
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    pub const SIGNATURE: &'static [u8; 4] = b"FACP";
+
+    const RESET_REG_SUPPORTED: u32 = 1 << 10;
+    const TIMER_32_BITS: u32 = 1 << 8;
+    const BOOT_ARCH_8042: u16 = 1 << 1;
+
+    pub fn parse(sdt: &Sdt) -> Option<Fadt> {
+        let flags = sdt.read(112).unwrap_or(0);
+        let dsdt = match sdt.read::<u64>(140) {
+            Some(x_dsdt) if x_dsdt != 0 => x_dsdt,
+            _ => sdt.read::<u32>(40)? as u64,
+        };
+        Some(Fadt {
+            dsdt,
+            sci_interrupt: sdt.read(46)?,
+            smi_command_port: sdt.read(48)?,
+            acpi_enable: sdt.read(52)?,
+            acpi_disable: sdt.read(53)?,
+            pm1a_event_block: sdt.read(56)?,
+            pm1b_event_block: sdt.read(60)?,
+            pm1a_control_block: sdt.read(64)?,
+            pm1b_control_block: sdt.read(68)?,
+            pm_timer_block: sdt.read(76)?,
+            pm_timer_length: sdt.read(91)?,
+            century: sdt.read(108).unwrap_or(0),
+            boot_architecture: sdt.read(109).unwrap_or(0),
+            flags,
+            reset_register: if flags & Self::RESET_REG_SUPPORTED != 0 { sdt.read(116) } else { None },
+            reset_value: sdt.read(128).unwrap_or(0),
+        })
+    }
+
+    /// Return whether the PM timer counts on 32 bits instead of 24
+    pub fn timer_32_bits(&self) -> bool {
+        self.flags & Self::TIMER_32_BITS != 0
+    }
+
+    /// Return whether there is a PS/2 controller, assumed for revision 1
+    pub fn has_8042(&self) -> bool {
+        self.boot_architecture == 0 || self.boot_architecture & Self::BOOT_ARCH_8042 != 0
+    }
+}
@@ -0,0 +1,38 @@
+use x86_64::PhysAddr;
+use crate::acpi::sdt::{GenericAddress, Sdt};
+
+/// The High Precision Event Timer description
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    /// Physical address of the timer registers
+    pub address: PhysAddr,
+    pub hpet_number: u8,
+    pub pci_vendor_id: u16,
+    /// Number of comparators, the timers that can raise interrupts
+    pub comparators: u8,
+    pub counter_64_bits: bool,
+    pub legacy_replacement: bool,
+    /// Minimum period of periodic interrupts, in counter ticks
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    pub const SIGNATURE: &'static [u8; 4] = b"HPET";
+
+    pub fn parse(sdt: &Sdt) -> Option<Hpet> {
+        let block_id: u32 = sdt.read(36)?;
+        let base: GenericAddress = sdt.read(40)?;
+        if base.address_space != GenericAddress::SYSTEM_MEMORY {
+            return None;
+        }
+        Some(Hpet {
+            address: PhysAddr::new(base.address),
+            hpet_number: sdt.read(52)?,
+            pci_vendor_id: (block_id >> 16) as u16,
+            comparators: ((block_id >> 8) & 0x1f) as u8 + 1,
+            counter_64_bits: block_id & (1 << 13) != 0,
+            legacy_replacement: block_id & (1 << 15) != 0,
+            minimum_tick: sdt.read(53)?,
+        })
+    }
+}
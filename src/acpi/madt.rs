@@ -0,0 +1,144 @@
+use alloc::vec::Vec;
+use core::mem;
+use x86_64::PhysAddr;
+use crate::acpi::sdt::{Sdt, SdtHeader};
+
+// entry types
+const LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const INTERRUPT_OVERRIDE: u8 = 2;
+const LOCAL_APIC_NMI: u8 = 4;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// A processor and its local APIC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// The processor can be started, false for absent hot-plug slots
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First global system interrupt of its inputs
+    pub gsi_base: u32,
+}
+
+/// The input an ISA interrupt is wired to, and how it signals
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    /// The ISA default: same input number, edge triggered, active high
+    pub fn identity(irq: u8) -> Self {
+        InterruptOverride { irq, gsi: irq as u32, flags: 0 }
+    }
+
+    pub fn active_low(&self) -> bool {
+        self.flags & 0b11 == 0b11
+    }
+
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0b11 == 0b11
+    }
+}
+
+/// Local APIC input wired to the non maskable interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    /// 0xff for all processors
+    pub processor_id: u8,
+    pub flags: u16,
+    pub lint: u8,
+}
+
+/// The Multiple APIC Description Table, the interrupt controllers
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// A dual 8259 PIC is present and must be masked to use the APICs
+    pub pic_compatible: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+impl Madt {
+    pub const SIGNATURE: &'static [u8; 4] = b"APIC";
+
+    pub fn parse(sdt: &Sdt) -> Option<Madt> {
+        let header = mem::size_of::<SdtHeader>();
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(sdt.read::<u32>(header)? as u64),
+            pic_compatible: sdt.read::<u32>(header + 4)? & 1 != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+
+        let mut offset = header + 8;
+        while let (Some(kind), Some(length)) = (sdt.read::<u8>(offset), sdt.read::<u8>(offset + 1)) {
+            if length < 2 {
+                break;
+            }
+            madt.parse_entry(sdt, kind, offset + 2);
+            offset += length as usize;
+        }
+        Some(madt)
+    }
+
+    /// Parse the entry of type `kind` whose fields start at `at`, ignoring
+    /// unknown and truncated ones
+    fn parse_entry(&mut self, sdt: &Sdt, kind: u8, at: usize) -> Option<()> {
+        match kind {
+            LOCAL_APIC => self.processors.push(Processor {
+                processor_id: sdt.read(at)?,
+                apic_id: sdt.read(at + 1)?,
+                enabled: sdt.read::<u32>(at + 2)? & 1 != 0,
+            }),
+            IO_APIC => self.io_apics.push(IoApic {
+                id: sdt.read(at)?,
+                address: PhysAddr::new(sdt.read::<u32>(at + 2)? as u64),
+                gsi_base: sdt.read(at + 6)?,
+            }),
+            INTERRUPT_OVERRIDE => self.overrides.push(InterruptOverride {
+                irq: sdt.read(at + 1)?,
+                gsi: sdt.read(at + 2)?,
+                flags: sdt.read(at + 6)?,
+            }),
+            LOCAL_APIC_NMI => self.nmis.push(LocalApicNmi {
+                processor_id: sdt.read(at)?,
+                flags: sdt.read(at + 1)?,
+                lint: sdt.read(at + 3)?,
+            }),
+            LOCAL_APIC_ADDRESS_OVERRIDE => {
+                self.local_apic_address = PhysAddr::new(sdt.read(at + 2)?);
+            }
+            _ => {}
+        }
+        Some(())
+    }
+
+    /// Return where ISA interrupt `irq` arrives
+    pub fn isa_irq(&self, irq: u8) -> InterruptOverride {
+        self.overrides.iter()
+            .find(|o| o.irq == irq)
+            .copied()
+            .unwrap_or_else(|| InterruptOverride::identity(irq))
+    }
+
+    /// Return the I/O APIC with input `gsi`, the one with the highest base
+    /// below it as the table does not give their sizes
+    pub fn io_apic_for(&self, gsi: u32) -> Option<&IoApic> {
+        self.io_apics.iter().filter(|io_apic| io_apic.gsi_base <= gsi).max_by_key(|io_apic| io_apic.gsi_base)
+    }
+}
@@ -0,0 +1,148 @@
+pub mod sdt;
+pub mod madt;
+pub mod fadt;
+pub mod hpet;
+
+use alloc::vec::Vec;
+use core::{mem, ptr, slice};
+use spin::Once;
+use x86_64::PhysAddr;
+use crate::memory::memory_management::phys_to_virt;
+use self::fadt::Fadt;
+use self::hpet::Hpet;
+use self::madt::Madt;
+use self::sdt::{ascii, checksum, Sdt};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// No RSDP in the BIOS areas, the machine has no ACPI or boots with UEFI
+    NoRsdp,
+    /// The structure at this address does not add up to 0
+    BadChecksum(PhysAddr),
+    /// The RSDP does not point to an RSDT or an XSDT
+    BadRootTable(PhysAddr),
+}
+
+/// Root System Description Pointer, revision 2 adds the XSDT
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // revision 2
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+const RSDP_V1_SIZE: usize = 20;
+
+/// What the firmware tables say about the machine
+#[derive(Debug)]
+pub struct Acpi {
+    /// 0 for ACPI 1.0, 2 and later have an XSDT
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub rsdp: PhysAddr,
+    /// Every table listed by the RSDT or XSDT with a valid checksum
+    pub tables: Vec<Sdt>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+}
+
+impl Acpi {
+    pub fn oem_id(&self) -> &str {
+        ascii(&self.oem_id)
+    }
+
+    /// Return the first table with `signature`
+    pub fn find(&self, signature: &[u8; 4]) -> Option<&Sdt> {
+        self.tables.iter().find(|sdt| sdt.signature() == signature)
+    }
+}
+
+static ACPI: Once<Result<Acpi, AcpiError>> = Once::new();
+
+/// Find and parse the ACPI tables, the result is kept for `tables`.
+///
+/// Must be called once the heap and the physical memory mapping are set up.
+pub fn init() -> Result<&'static Acpi, AcpiError> {
+    ACPI.call_once(|| unsafe { parse(find_rsdp()?) }).as_ref().map_err(|error| *error)
+}
+
+/// Return the tables parsed by `init`
+pub fn tables() -> Option<&'static Acpi> {
+    ACPI.r#try()?.as_ref().ok()
+}
+
+unsafe fn bios_area(start: u64, size: usize) -> &'static [u8] {
+    slice::from_raw_parts(phys_to_virt(PhysAddr::new(start)).as_ptr(), size)
+}
+
+/// Search the first KiB of the extended BIOS data area, then the BIOS ROM,
+/// for the RSDP on a 16 byte boundary
+fn find_rsdp() -> Result<PhysAddr, AcpiError> {
+    let ebda = unsafe { ptr::read_unaligned(phys_to_virt(PhysAddr::new(0x40e)).as_ptr::<u16>()) as u64 } << 4;
+    let areas = [(ebda, 1024), (0xe_0000, 0x2_0000)];
+
+    for &(start, size) in areas.iter().filter(|&&(start, _)| start != 0) {
+        let area = unsafe { bios_area(start, size) };
+        for offset in (0..size - RSDP_V1_SIZE).step_by(16) {
+            if &area[offset..offset + 8] == b"RSD PTR " && checksum(&area[offset..offset + RSDP_V1_SIZE]) {
+                return Ok(PhysAddr::new(start + offset as u64));
+            }
+        }
+    }
+    Err(AcpiError::NoRsdp)
+}
+
+/// Read the root table the RSDP at `addr` points to and every table it lists
+unsafe fn parse(addr: PhysAddr) -> Result<Acpi, AcpiError> {
+    let rsdp: Rsdp = ptr::read_unaligned(phys_to_virt(addr).as_ptr());
+    let extended = rsdp.revision >= 2 && rsdp.xsdt_address != 0;
+    if extended && !checksum(bios_area(addr.as_u64(), rsdp.length as usize)) {
+        return Err(AcpiError::BadChecksum(addr));
+    }
+
+    let (root, signature, entry_size) = if extended {
+        (Sdt::at(PhysAddr::new(rsdp.xsdt_address))?, b"XSDT", mem::size_of::<u64>())
+    } else {
+        (Sdt::at(PhysAddr::new(rsdp.rsdt_address as u64))?, b"RSDT", mem::size_of::<u32>())
+    };
+    if root.signature() != signature {
+        return Err(AcpiError::BadRootTable(root.addr));
+    }
+
+    let mut tables = Vec::new();
+    for offset in (mem::size_of::<sdt::SdtHeader>()..root.len()).step_by(entry_size) {
+        let table = if extended {
+            root.read::<u64>(offset)
+        } else {
+            root.read::<u32>(offset).map(u64::from)
+        };
+        // skip broken tables rather than losing the others
+        if let Some(Ok(sdt)) = table.map(|table| Sdt::at(PhysAddr::new(table))) {
+            tables.push(sdt);
+        }
+    }
+
+    let mut acpi = Acpi {
+        revision: rsdp.revision,
+        oem_id: rsdp.oem_id,
+        rsdp: addr,
+        tables,
+        madt: None,
+        fadt: None,
+        hpet: None,
+    };
+    acpi.madt = acpi.find(Madt::SIGNATURE).and_then(Madt::parse);
+    acpi.fadt = acpi.find(Fadt::SIGNATURE).and_then(Fadt::parse);
+    acpi.hpet = acpi.find(Hpet::SIGNATURE).and_then(Hpet::parse);
+    Ok(acpi)
+}
@@ -0,0 +1,89 @@
+use core::{mem, ptr, slice, str};
+use x86_64::PhysAddr;
+use crate::acpi::AcpiError;
+use crate::memory::memory_management::phys_to_virt;
+
+/// Header shared by every system description table
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Register location used by the FADT and HPET tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    /// 0 for memory, 1 for I/O ports
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+}
+
+/// Return whether the bytes add up to 0, as every ACPI structure must
+pub fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Return `bytes` as text, ACPI identifiers are ASCII padded with spaces
+pub fn ascii(bytes: &[u8]) -> &str {
+    str::from_utf8(bytes).unwrap_or("?").trim_end()
+}
+
+/// A validated table, read through the physical memory mapping
+#[derive(Debug, Clone, Copy)]
+pub struct Sdt {
+    pub addr: PhysAddr,
+    pub header: SdtHeader,
+}
+
+impl Sdt {
+    /// Read the table at `addr` and check its checksum.
+    ///
+    /// This function is unsafe because `addr` must point to a table.
+    pub unsafe fn at(addr: PhysAddr) -> Result<Sdt, AcpiError> {
+        let header: SdtHeader = ptr::read_unaligned(phys_to_virt(addr).as_ptr());
+        let sdt = Sdt { addr, header };
+        if (header.length as usize) < mem::size_of::<SdtHeader>() || !checksum(sdt.bytes()) {
+            return Err(AcpiError::BadChecksum(addr));
+        }
+        Ok(sdt)
+    }
+
+    pub fn signature(&self) -> &[u8; 4] {
+        &self.header.signature
+    }
+
+    pub fn len(&self) -> usize {
+        self.header.length as usize
+    }
+
+    /// Return the whole table, header included
+    pub fn bytes(&self) -> &'static [u8] {
+        unsafe { slice::from_raw_parts(phys_to_virt(self.addr).as_ptr(), self.len()) }
+    }
+
+    /// Read a `T` at `offset` from the start of the table, `None` past its
+    /// end as older revisions of a table are shorter
+    pub fn read<T: Copy>(&self, offset: usize) -> Option<T> {
+        if offset + mem::size_of::<T>() > self.len() {
+            return None;
+        }
+        Some(unsafe { ptr::read_unaligned(self.bytes()[offset..].as_ptr() as *const T) })
+    }
+}
@@ -0,0 +1,106 @@
+/// Fixed-size hash table keyed by address, for allocator bookkeeping
+/// that must not live on the heap it describes.
+
+const TABLE_BITS: usize = 12;
+
+/// Number of slots, one is always kept empty
+pub const TABLE_SIZE: usize = 1 << TABLE_BITS;
+
+/// A table slot, free while `addr` is 0
+#[derive(Clone, Copy)]
+pub struct Slot<T> {
+    pub addr: usize,
+    pub value: T,
+}
+
+/// Open addressing table with linear probing and backward shift deletion
+pub struct AddrTable<T> {
+    slots: [Slot<T>; TABLE_SIZE],
+    count: usize,
+}
+
+impl<T> AddrTable<T> {
+    /// Create a table from empty slots
+    pub const fn new(slots: [Slot<T>; TABLE_SIZE]) -> Self {
+        AddrTable { slots, count: 0 }
+    }
+
+    /// Return the number of entries
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    fn home(addr: usize) -> usize {
+        (addr >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - TABLE_BITS)
+    }
+}
+
+impl<T: Copy> AddrTable<T> {
+    /// Insert `value` for `addr`, which must not be in the table.
+    /// Returns `false` if the table is full.
+    pub fn insert(&mut self, addr: usize, value: T) -> bool {
+        if self.count == TABLE_SIZE - 1 {
+            return false;
+        }
+        let mut i = Self::home(addr);
+        while self.slots[i].addr != 0 {
+            i = (i + 1) % TABLE_SIZE;
+        }
+        self.slots[i] = Slot { addr, value };
+        self.count += 1;
+        true
+    }
+
+    /// Return the value stored for `addr`
+    pub fn get(&self, addr: usize) -> Option<&T> {
+        self.find(addr).map(|i| &self.slots[i].value)
+    }
+
+    /// Remove and return the value stored for `addr`
+    pub fn remove(&mut self, addr: usize) -> Option<T> {
+        let index = self.find(addr)?;
+        let value = self.slots[index].value;
+
+        // shift back the entries probing past the hole
+        let mut hole = index;
+        let mut j = index;
+        loop {
+            j = (j + 1) % TABLE_SIZE;
+            if self.slots[j].addr == 0 {
+                break;
+            }
+            let home = Self::home(self.slots[j].addr);
+            let reachable = if hole <= j {
+                hole < home && home <= j
+            } else {
+                hole < home || home <= j
+            };
+            if !reachable {
+                self.slots[hole] = self.slots[j];
+                hole = j;
+            }
+        }
+        self.slots[hole].addr = 0;
+        self.count -= 1;
+        Some(value)
+    }
+
+    /// Return an iterator over the entries
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.slots
+            .iter()
+            .filter(|slot| slot.addr != 0)
+            .map(|slot| (slot.addr, &slot.value))
+    }
+
+    fn find(&self, addr: usize) -> Option<usize> {
+        let mut i = Self::home(addr);
+        while self.slots[i].addr != 0 {
+            if self.slots[i].addr == addr {
+                return Some(i);
+            }
+            i = (i + 1) % TABLE_SIZE;
+        }
+        None
+    }
+}
@@ -1,18 +1,49 @@
-pub const HEAP_START: usize = 0x_4444_4444_0000;
+// aligned to HEAP_MAX_SIZE, so that grown memory can form blocks of any size
+pub const HEAP_START: usize = 0x_4444_0000_0000;
 pub const HEAP_SIZE: usize = 1 * 1024 * 1024; // 1 MB
+/// Virtual memory reserved for the heap, the largest ceiling `init_heap` accepts
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024; // 16 MB
+pub const HEAP_GROW_STEP: usize = 256 * 1024; // 256 KB
 
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
+use crate::ALLOCATOR;
+use crate::allocator::kernel_allocator::HeapStats;
+#[cfg(any(feature = "alloc-buddy", feature = "alloc-slab"))]
 use crate::BUDDY_ALLOCATOR;
+#[cfg(any(feature = "alloc-buddy", feature = "alloc-slab"))]
+use crate::allocator::buddy_system::bitmap::{bitmap_words, FreeBitmap};
+use crate::memory::frame_manager::{self, FrameManager};
+use crate::memory::memory_management::try_with_mapper;
+use crate::sync::{SpinLock, SpinLockGuard};
 use bootloader::BootInfo;
 
+/// First address after the mapped part of the heap.
+#[cfg(any(feature = "alloc-buddy", feature = "alloc-slab"))]
+static HEAP_END: AtomicUsize = AtomicUsize::new(HEAP_START + HEAP_SIZE);
+/// Size the heap may grow to, set by `init_heap`.
+#[cfg(any(feature = "alloc-buddy", feature = "alloc-slab"))]
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_SIZE);
+/// Free block bitmap of the kernel heap, covering its whole possible range.
+#[cfg(any(feature = "alloc-buddy", feature = "alloc-slab"))]
+static mut HEAP_BITMAP: [usize; bitmap_words(HEAP_MAX_SIZE)] = [0; bitmap_words(HEAP_MAX_SIZE)];
+/// Held while the heap is being extended. Preemption stays disabled, so
+/// it is only ever found locked by code nested in the growth on this CPU.
+#[cfg(any(feature = "alloc-buddy", feature = "alloc-slab"))]
+static GROW_LOCK: SpinLock<()> = SpinLock::new(());
+
+/// Map the initial heap and let it grow up to `max_size` bytes, which is
+/// rounded to pages and capped to `HEAP_MAX_SIZE`.
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    max_size: usize,
 ) -> Result<(), MapToError<Size4KiB>> {
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
@@ -32,29 +63,122 @@ pub fn init_heap(
         };
     }
 
+    // only the buddy heap tracks free blocks in a bitmap and can grow
+    #[cfg(any(feature = "alloc-buddy", feature = "alloc-slab"))]
+    {
+        BUDDY_ALLOCATOR.lock().set_bitmap(unsafe {
+            FreeBitmap::new(HEAP_START, HEAP_MAX_SIZE, &mut HEAP_BITMAP)
+        });
+        HEAP_LIMIT.store(align_up(max_size, 4096).max(HEAP_SIZE).min(HEAP_MAX_SIZE), Ordering::Relaxed);
+        BUDDY_ALLOCATOR.set_grow_handler(grow_heap);
+    }
+    #[cfg(not(any(feature = "alloc-buddy", feature = "alloc-slab")))]
+    let _ = max_size;
+
     unsafe {
-        BUDDY_ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.init(HEAP_START, HEAP_SIZE);
     }
 
     Ok(())
 }
 
+/// Return a snapshot of the kernel heap statistics, whichever allocator backs it
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
+/// Return the size the heap may grow to
+#[cfg(any(feature = "alloc-buddy", feature = "alloc-slab"))]
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Return the first address after the mapped part of the heap
+#[cfg(feature = "alloc-debug")]
+pub fn heap_end() -> usize {
+    #[cfg(any(feature = "alloc-buddy", feature = "alloc-slab"))]
+    return HEAP_END.load(Ordering::Relaxed);
+    #[cfg(not(any(feature = "alloc-buddy", feature = "alloc-slab")))]
+    return HEAP_START + HEAP_SIZE;
+}
+
+/// Map fresh pages after the current heap end and add them to the heap.
+///
+/// Called by the heap when an allocation of `layout` fails. The heap grows by
+/// at least `HEAP_GROW_STEP` bytes, up to an aligned block large enough for
+/// `layout`, and never beyond `heap_limit()`. Returns `false` if nothing
+/// could be mapped.
+///
+/// Growths are serialized, a thread never sees one in progress as the
+/// grower cannot be preempted. The lock is only found held by an interrupt
+/// handler or by an allocation made while growing, which the growth cannot
+/// finish before, so those fail instead of waiting.
+///
+/// The frame allocator and the page table do not allocate from the heap, but
+/// the failed allocation may come from code holding them, so they are only
+/// tried and the growth fails if either is locked.
+#[cfg(any(feature = "alloc-buddy", feature = "alloc-slab"))]
+fn grow_heap(layout: Layout) -> bool {
+    let _growing = match GROW_LOCK.try_lock() {
+        Some(guard) => guard,
+        None => return false,
+    };
+
+    let heap_end = HEAP_END.load(Ordering::Relaxed);
+    let heap_limit = HEAP_START + heap_limit();
+    // buddy blocks are aligned to their size, the block must fit after the end
+    let block = layout.size().max(layout.align()).next_power_of_two();
+    let block_end = match align_up(heap_end, block).checked_add(block) {
+        Some(end) if end <= heap_limit => end,
+        _ => return false,
+    };
+    let new_end = block_end.max(heap_end + HEAP_GROW_STEP).min(heap_limit);
+
+    let mut mapped_end = heap_end;
+    try_with_mapper(|mapper| {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        while mapped_end < new_end {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(mapped_end as u64));
+            let frame = match frame_manager::try_allocate_frame() {
+                Some(frame) => frame,
+                None => break,
+            };
+            match unsafe { mapper.map_to(page, frame, flags, &mut FrameManager) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    unsafe { FrameManager.deallocate_frame(frame) };
+                    break;
+                }
+            }
+            mapped_end += 4096;
+        }
+    });
+
+    if mapped_end > heap_end {
+        unsafe {
+            BUDDY_ALLOCATOR.lock().extend(heap_end, mapped_end);
+        }
+        HEAP_END.store(mapped_end, Ordering::Relaxed);
+    }
+    mapped_end > heap_end
+}
+
 pub fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
 
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: SpinLock<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: SpinLock::new(inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> SpinLockGuard<A> {
         self.inner.lock()
     }
 }
@@ -0,0 +1,138 @@
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::arch::x86_64::_rdtsc;
+use core::mem::size_of;
+use core::ptr::NonNull;
+use crate::allocator::buddy_system::bitmap::{bitmap_words, FreeBitmap};
+use crate::allocator::buddy_system::buddy_manager::Heap;
+
+/// Size of the region each benchmarked heap manages
+const ARENA_SIZE: usize = 256 * 1024;
+
+#[repr(align(4096))]
+struct Arena([u8; ARENA_SIZE]);
+
+static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+static mut ARENA_BITMAP: [usize; bitmap_words(ARENA_SIZE)] = [0; bitmap_words(ARENA_SIZE)];
+
+/// Cycles spent by both buddy lookup paths on one workload
+#[derive(Debug, Clone, Copy)]
+pub struct BenchResult {
+    pub workload: &'static str,
+    pub linear_cycles: u64,
+    pub bitmap_cycles: u64,
+    /// Whether both paths handed out the same blocks and merged the heap
+    /// back to the same free blocks, so they found the same buddies
+    pub same_buddies: bool,
+}
+
+/// What a workload left behind, equal for both paths if they agree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Outcome {
+    /// Hash of the addresses handed out, in order
+    blocks: usize,
+    free_blocks: [usize; 32],
+    largest_free: usize,
+}
+
+type Workload = fn(&mut Heap, &mut Vec<NonNull<u8>>);
+
+/// Allocation patterns of the heap tests
+const WORKLOADS: [(&str, Workload); 4] = [
+    ("alloc_and_free", alloc_and_free),
+    ("many_boxes_long_lived", many_boxes_long_lived),
+    ("large_vec", large_vec),
+    ("fragmented", fragmented),
+];
+
+/// Run every workload on a heap walking its free lists to find buddies
+/// and on a heap using the free block bitmap, counting TSC cycles.
+/// Debug builds cross-check the bitmap against the free lists, so only
+/// release builds show the cost of the bitmap path alone.
+pub fn compare_dealloc_paths() -> Vec<BenchResult> {
+    let mut blocks = Vec::with_capacity(ARENA_SIZE >> 4);
+    WORKLOADS
+        .iter()
+        .map(|&(workload, run)| {
+            let (linear_cycles, linear) = measure(run, false, &mut blocks);
+            let (bitmap_cycles, bitmap) = measure(run, true, &mut blocks);
+            BenchResult { workload, linear_cycles, bitmap_cycles, same_buddies: linear == bitmap }
+        })
+        .collect()
+}
+
+fn measure(run: Workload, with_bitmap: bool, blocks: &mut Vec<NonNull<u8>>) -> (u64, Outcome) {
+    let mut heap = Heap::new();
+    unsafe {
+        let start = ARENA.0.as_mut_ptr() as usize;
+        if with_bitmap {
+            heap.set_bitmap(FreeBitmap::new(start, ARENA_SIZE, &mut ARENA_BITMAP));
+        }
+        heap.add_to_heap(start, start + ARENA_SIZE);
+    }
+    blocks.clear();
+
+    let begin = unsafe { _rdtsc() };
+    run(&mut heap, blocks);
+    let cycles = unsafe { _rdtsc() } - begin;
+
+    assert_eq!(heap.stats_alloc_actual(), 0, "workload leaked heap memory");
+    let stats = heap.stats();
+    let outcome = Outcome {
+        blocks: blocks.iter().fold(0, |hash, block| hash.wrapping_mul(31) ^ block.as_ptr() as usize),
+        free_blocks: stats.free_blocks,
+        largest_free: stats.largest_free,
+    };
+    (cycles, outcome)
+}
+
+fn layout(size: usize) -> Layout {
+    Layout::from_size_align(size, 1).unwrap()
+}
+
+fn alloc_and_free(heap: &mut Heap, blocks: &mut Vec<NonNull<u8>>) {
+    for _ in 0..100 {
+        let addr = heap.alloc(layout(1)).unwrap();
+        blocks.push(addr);
+        heap.dealloc(addr, layout(1));
+    }
+}
+
+fn many_boxes_long_lived(heap: &mut Heap, blocks: &mut Vec<NonNull<u8>>) {
+    let long_lived = heap.alloc(layout(size_of::<usize>())).unwrap();
+    blocks.push(long_lived);
+    for _ in 0..10_000 {
+        let addr = heap.alloc(layout(size_of::<usize>())).unwrap();
+        blocks.push(addr);
+        heap.dealloc(addr, layout(size_of::<usize>()));
+    }
+    heap.dealloc(long_lived, layout(size_of::<usize>()));
+}
+
+fn large_vec(heap: &mut Heap, blocks: &mut Vec<NonNull<u8>>) {
+    // capacity doubling of a Vec growing to 1000 u64
+    let mut size = 4 * size_of::<u64>();
+    let mut current = heap.alloc(layout(size)).unwrap();
+    blocks.push(current);
+    while size < 1000 * size_of::<u64>() {
+        let grown = heap.alloc(layout(2 * size)).unwrap();
+        blocks.push(grown);
+        heap.dealloc(current, layout(size));
+        current = grown;
+        size *= 2;
+    }
+    heap.dealloc(current, layout(size));
+}
+
+fn fragmented(heap: &mut Heap, blocks: &mut Vec<NonNull<u8>>) {
+    for _ in 0..4096 {
+        blocks.push(heap.alloc(layout(16)).unwrap());
+    }
+    // free every other block first so the free lists fill up
+    for addr in blocks.iter().step_by(2) {
+        heap.dealloc(*addr, layout(16));
+    }
+    for addr in blocks.iter().skip(1).step_by(2) {
+        heap.dealloc(*addr, layout(16));
+    }
+}
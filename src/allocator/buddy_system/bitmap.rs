@@ -0,0 +1,105 @@
+use core::mem::size_of;
+use crate::allocator::buddy_system::buddy_manager::MIN_ORDER;
+
+const WORD_BITS: usize = 8 * size_of::<usize>();
+
+/// Number of words a `FreeBitmap` needs to track `span` bytes
+pub const fn bitmap_words(span: usize) -> usize {
+    bitmap_words_from(span, MIN_ORDER)
+}
+
+/// Number of words a `FreeBitmap` needs to track `span` units in blocks of
+/// `min_order` and up
+pub const fn bitmap_words_from(span: usize, min_order: usize) -> usize {
+    // every order below the top one uses at most (span >> order) + 1 bits
+    (2 * (span >> min_order) + 32) / WORD_BITS + 1
+}
+
+/// Free state of every possible block of a heap region, one bitmap per order.
+/// A bit is set while the block of that order starting at that address
+/// sits in the free list, so a buddy can be looked up in constant time.
+pub struct FreeBitmap {
+    base: usize,
+    span: usize,
+    offsets: [usize; 32],
+    words: &'static mut [usize],
+}
+
+impl FreeBitmap {
+    /// Track the region [base, base + span) using `words` as storage.
+    /// `words` must hold at least `bitmap_words(span)` entries.
+    pub fn new(base: usize, span: usize, words: &'static mut [usize]) -> Self {
+        Self::with_min_order(base, span, MIN_ORDER, words)
+    }
+
+    /// Track the region [base, base + span) in blocks of `min_order` and up.
+    /// `words` must hold at least `bitmap_words_from(span, min_order)` entries.
+    pub fn with_min_order(base: usize, span: usize, min_order: usize, words: &'static mut [usize]) -> Self {
+        let mut offsets = [0; 32];
+        let mut offset = 0;
+        for order in min_order..offsets.len() {
+            offsets[order] = offset;
+            offset += (span >> order) + 1;
+        }
+        assert!(offset <= words.len() * WORD_BITS, "bitmap storage too small");
+
+        for word in words.iter_mut() {
+            *word = 0;
+        }
+
+        FreeBitmap {
+            base,
+            span,
+            offsets,
+            words,
+        }
+    }
+
+    /// Return `true` if blocks at `addr` are tracked by the bitmap
+    pub fn covers(&self, addr: usize) -> bool {
+        addr >= self.base && addr - self.base < self.span
+    }
+
+    /// Mark the block of `order` at `addr` free
+    pub fn set(&mut self, order: usize, addr: usize) {
+        let (word, bit) = self.position(order, addr);
+        self.words[word] |= bit;
+    }
+
+    /// Mark the block of `order` at `addr` not free
+    pub fn clear(&mut self, order: usize, addr: usize) {
+        let (word, bit) = self.position(order, addr);
+        self.words[word] &= !bit;
+    }
+
+    /// Return `true` if the block of `order` at `addr` is free
+    pub fn test(&self, order: usize, addr: usize) -> bool {
+        let (word, bit) = self.position(order, addr);
+        self.words[word] & bit != 0
+    }
+
+    /// Return the lowest free block of `order` that is not below the
+    /// `from`-th block of that order
+    pub fn first_free(&self, order: usize, from: usize) -> Option<usize> {
+        let end = self.offsets[order] + (self.span >> order) + 1;
+        let mut index = self.offsets[order] + from;
+        while index < end {
+            let word = self.words[index / WORD_BITS] >> (index % WORD_BITS);
+            if word == 0 {
+                index = (index / WORD_BITS + 1) * WORD_BITS;
+                continue;
+            }
+            let found = index + word.trailing_zeros() as usize;
+            if found >= end {
+                return None;
+            }
+            return Some(self.base + ((found - self.offsets[order]) << order));
+        }
+        None
+    }
+
+    fn position(&self, order: usize, addr: usize) -> (usize, usize) {
+        let index = self.offsets[order] + ((addr - self.base) >> order);
+        (index / WORD_BITS, 1 << (index % WORD_BITS))
+    }
+}
@@ -5,30 +5,50 @@ use core::mem::size_of;
 use core::ops::Deref;
 use core::ptr::NonNull;
 use core::ptr;
-use spin::Mutex;
-use crate::allocator::buddy_system::linked_list;
+use spin::Once;
+use crate::allocator::buddy_system::free_list::FreeList;
+use crate::allocator::buddy_system::bitmap::FreeBitmap;
+use crate::allocator::kernel_allocator::{HeapStats, KernelAllocator};
 use crate::serial_println;
+use crate::sync::SpinLock;
+
+/// Order of the smallest block, large enough to hold the free list links
+pub const MIN_ORDER: usize = (2 * size_of::<usize>()).trailing_zeros() as usize;
 
 /// A heap that uses buddy system
 /// Create a heap and add a memory region to it:
 pub struct Heap {
     // buddy system with max order of 32
-    free_list: [linked_list::LinkedList; 32],
+    free_list: [FreeList; 32],
+
+    // free blocks of each order, for constant time buddy lookup
+    bitmap: Option<FreeBitmap>,
 
     // statistics
     user: usize,
     allocated: usize,
     total: usize,
+    growths: usize,
+    peak: usize,
+    allocs: usize,
+    frees: usize,
+    failed: usize,
 }
 
 impl Heap {
     /// Create an empty heap
     pub const fn new() -> Self {
         Heap {
-            free_list: [linked_list::LinkedList::new(); 32],
+            free_list: [FreeList::new(); 32],
+            bitmap: None,
             user: 0,
             allocated: 0,
             total: 0,
+            growths: 0,
+            peak: 0,
+            allocs: 0,
+            frees: 0,
+            failed: 0,
         }
     }
 
@@ -37,22 +57,37 @@ impl Heap {
         Self::new()
     }
 
+    /// Track free blocks in `bitmap`, making buddy lookup constant time.
+    /// Must be set before any memory is added, and all memory added
+    /// afterwards, grown memory included, must lie inside its region.
+    pub fn set_bitmap(&mut self, bitmap: FreeBitmap) {
+        assert_eq!(self.total, 0, "bitmap must be set on an empty heap");
+        self.bitmap = Some(bitmap);
+    }
+
     /// Add a range of memory [start, end) to the heap
     pub unsafe fn add_to_heap(&mut self, mut start: usize, mut end: usize) {
-        // avoid unaligned access on some platforms
-        start = (start + size_of::<usize>() - 1) & (!size_of::<usize>() + 1);
-        end = end & (!size_of::<usize>() + 1);
+        // every block must be able to hold the free list links
+        let min_block = 1 << MIN_ORDER;
+        start = (start + min_block - 1) & (!min_block + 1);
+        end = end & (!min_block + 1);
         assert!(start <= end);
+        if let Some(bitmap) = &self.bitmap {
+            assert!(
+                start == end || (bitmap.covers(start) && bitmap.covers(end - 1)),
+                "memory added outside the free block bitmap"
+            );
+        }
 
         let mut total = 0;
         let mut current_start = start;
 
-        while current_start + size_of::<usize>() <= end {
+        while current_start + min_block <= end {
             let lowbit = current_start & (!current_start + 1);
             let size = min(lowbit, prev_power_of_two(end - current_start));
             total += size;
 
-            self.free_list[size.trailing_zeros() as usize].push(current_start as *mut usize);
+            self.push_free(size.trailing_zeros() as usize, current_start);
             current_start += size;
         }
 
@@ -64,22 +99,34 @@ impl Heap {
         self.add_to_heap(start, start + size);
     }
 
+    /// Grow the heap with a freshly mapped range of memory [start, end)
+    pub unsafe fn extend(&mut self, start: usize, end: usize) {
+        self.add_to_heap(start, end);
+        self.growths += 1;
+    }
+
     /// Alloc a range of memory from the heap satifying `layout` requirements
     pub fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, ()> {
-        let size = max(
-            layout.size().next_power_of_two(),
-            max(layout.align(), size_of::<usize>()),
-        );
+        let result = self.take_block(layout);
+        if result.is_err() {
+            self.failed += 1;
+        }
+        result
+    }
+
+    /// Alloc without counting a failure, the caller may still recover
+    fn take_block(&mut self, layout: Layout) -> Result<NonNull<u8>, ()> {
+        let size = Self::block_size(&layout);
         let class = size.trailing_zeros() as usize;
         for i in class..self.free_list.len() {
             // Find the first non-empty size class
             if !self.free_list[i].is_empty() {
                 // Split buffers
                 for j in (class + 1 .. i + 1).rev() {
-                    if let Some(block) = self.free_list[j].pop() {
+                    if let Some(block) = self.pop_free(j) {
                         unsafe {
-                            self.free_list[j - 1].push((block as usize + (1 << (j - 1))) as *mut usize);
-                            self.free_list[j - 1].push(block);
+                            self.push_free(j - 1, block + (1 << (j - 1)));
+                            self.push_free(j - 1, block);
                         }
                     } else {
                         return Err(());
@@ -87,14 +134,15 @@ impl Heap {
                 }
 
                 let result = NonNull::new(
-                    self.free_list[class]
-                        .pop()
+                    self.pop_free(class)
                         .expect("current block should have free space now")
                         as *mut u8,
                 );
                 if let Some(result) = result {
                     self.user += layout.size();
                     self.allocated += size;
+                    self.peak = max(self.peak, self.allocated);
+                    self.allocs += 1;
                     return Ok(result);
                 } else {
                     return Err(());
@@ -106,44 +154,118 @@ impl Heap {
 
     /// Dealloc a range of memory from the heap
     pub fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
-        let size = max(
-            layout.size().next_power_of_two(),
-            max(layout.align(), size_of::<usize>()),
-        );
+        let size = Self::block_size(&layout);
         let class = size.trailing_zeros() as usize;
 
         unsafe {
-            // Put back into free list
-            self.free_list[class].push(ptr.as_ptr() as *mut usize);
-
             // Merge free buddy lists
             let mut current_ptr = ptr.as_ptr() as usize;
             let mut current_class = class;
-            while current_class < self.free_list.len() {
+            while current_class + 1 < self.free_list.len() {
                 let buddy = current_ptr ^ (1 << current_class);
-                let mut flag = false;
-                for block in self.free_list[current_class].iter_mut() {
-                    if block.value() as usize == buddy {
-                        block.pop();
-                        flag = true;
-                        break;
-                    }
+                if !self.take_free(current_class, buddy) {
+                    break;
                 }
 
                 // Free buddy found
-                if flag {
-                    self.free_list[current_class].pop();
-                    current_ptr = min(current_ptr, buddy);
-                    current_class += 1;
-                    self.free_list[current_class].push(current_ptr as *mut usize);
-                } else {
-                    break;
-                }
+                current_ptr = min(current_ptr, buddy);
+                current_class += 1;
             }
+
+            // Put back into free list
+            self.push_free(current_class, current_ptr);
         }
 
         self.user -= layout.size();
         self.allocated -= size;
+        self.frees += 1;
+    }
+
+    /// Resize the allocation at `ptr` to `new_size` bytes without moving it.
+    ///
+    /// Shrinking splits the block and gives the upper halves back to the free
+    /// lists, growing merges the block with its free buddies. Returns `None`
+    /// if the block can't grow in place, the allocation is then unchanged.
+    pub fn realloc_in_place(&mut self, ptr: NonNull<u8>, layout: Layout, new_size: usize) -> Option<NonNull<u8>> {
+        let new_layout = Layout::from_size_align(new_size, layout.align()).ok()?;
+        let addr = ptr.as_ptr() as usize;
+        let class = Self::block_size(&layout).trailing_zeros() as usize;
+        let new_class = Self::block_size(&new_layout).trailing_zeros() as usize;
+
+        if new_class > class {
+            // every buddy up to the new order must be free and above the block
+            for order in class..new_class {
+                if addr & (1 << order) != 0 || !self.is_free(order, addr + (1 << order)) {
+                    return None;
+                }
+            }
+            for order in class..new_class {
+                unsafe { self.take_free(order, addr + (1 << order)) };
+            }
+            self.allocated += (1 << new_class) - (1 << class);
+            self.peak = max(self.peak, self.allocated);
+        } else if new_class < class {
+            // the upper halves' buddies are still in use, nothing to merge
+            for order in (new_class..class).rev() {
+                unsafe { self.push_free(order, addr + (1 << order)) };
+            }
+            self.allocated -= (1 << class) - (1 << new_class);
+        }
+
+        self.user = self.user - layout.size() + new_size;
+        Some(ptr)
+    }
+
+    /// Return `true` if the block of `class` at `addr` is in the free lists.
+    /// With a bitmap, which covers all the memory of the heap, the free list
+    /// is only walked to cross-check it in debug builds.
+    fn is_free(&self, class: usize, addr: usize) -> bool {
+        match &self.bitmap {
+            Some(bitmap) => {
+                let free = bitmap.covers(addr) && bitmap.test(class, addr);
+                debug_assert_eq!(free, self.free_list[class].contains(addr as *mut usize));
+                free
+            }
+            None => self.free_list[class].contains(addr as *mut usize),
+        }
+    }
+
+    /// Return the size of the block serving `layout`
+    fn block_size(layout: &Layout) -> usize {
+        max(
+            layout.size().next_power_of_two(),
+            max(layout.align(), 1 << MIN_ORDER),
+        )
+    }
+
+    /// Put the block at `addr` into the free list of `class`
+    unsafe fn push_free(&mut self, class: usize, addr: usize) {
+        self.free_list[class].push(addr as *mut usize);
+        if let Some(bitmap) = &mut self.bitmap {
+            bitmap.set(class, addr);
+        }
+    }
+
+    /// Take the first block out of the free list of `class`
+    fn pop_free(&mut self, class: usize) -> Option<usize> {
+        let block = self.free_list[class].pop()? as usize;
+        if let Some(bitmap) = &mut self.bitmap {
+            bitmap.clear(class, block);
+        }
+        Some(block)
+    }
+
+    /// Take the block at `addr` out of the free list of `class` if it is free.
+    /// Constant time with a bitmap, a list walk without.
+    unsafe fn take_free(&mut self, class: usize, addr: usize) -> bool {
+        let free = self.is_free(class, addr);
+        if free {
+            self.free_list[class].remove(addr as *mut usize);
+            if let Some(bitmap) = &mut self.bitmap {
+                bitmap.clear(class, addr);
+            }
+        }
+        free
     }
 
     /// Return the number of bytes that user requests
@@ -161,9 +283,39 @@ impl Heap {
         self.total
     }
 
-    /// Return status of used block
+    /// Return the number of times the heap was extended
+    pub fn stats_growths(&self) -> usize {
+        self.growths
+    }
+
+    /// Return `true` if any block is allocated
     pub fn is_used(&self) -> bool {
-        self.allocated == 0
+        self.allocated != 0
+    }
+
+    /// Return a snapshot of the heap statistics
+    pub fn stats(&self) -> HeapStats {
+        let mut free_blocks = [0; 32];
+        let mut largest_free = 0;
+        for (order, list) in self.free_list.iter().enumerate() {
+            free_blocks[order] = list.len();
+            if !list.is_empty() {
+                largest_free = 1 << order;
+            }
+        }
+
+        HeapStats {
+            user: self.user,
+            allocated: self.allocated,
+            total: self.total,
+            free_blocks,
+            largest_free,
+            peak: self.peak,
+            allocs: self.allocs,
+            frees: self.frees,
+            failed: self.failed,
+            growths: self.growths,
+        }
     }
 }
 
@@ -173,46 +325,70 @@ impl fmt::Debug for Heap {
             .field("user", &self.user)
             .field("allocated", &self.allocated)
             .field("total", &self.total)
+            .field("growths", &self.growths)
+            .field("peak", &self.peak)
+            .field("allocs", &self.allocs)
+            .field("frees", &self.frees)
+            .field("failed", &self.failed)
             .finish()
     }
 }
 
 /// A locked version of `Heap`
 /// Create a locked heap and add a memory region to it:
-pub struct LockedHeap(Mutex<Heap>);
+pub struct LockedHeap(SpinLock<Heap>, Once<fn(Layout) -> bool>);
 
 impl LockedHeap {
     /// Creates an empty heap
     pub const fn new() -> LockedHeap {
-        LockedHeap(Mutex::new(Heap::new()))
+        LockedHeap(SpinLock::new(Heap::new()), Once::new())
     }
 
     /// Creates an empty heap
     pub const fn empty() -> LockedHeap {
-        LockedHeap(Mutex::new(Heap::new()))
+        LockedHeap(SpinLock::new(Heap::new()), Once::new())
+    }
+
+    /// Register the function called when an allocation fails.
+    ///
+    /// It runs without the heap lock held and returns `true` if it extended
+    /// the heap, in which case the allocation is retried, as long as the
+    /// heap keeps growing.
+    pub fn set_grow_handler(&self, grow: fn(Layout) -> bool) {
+        self.1.call_once(|| grow);
+    }
+
+    fn try_alloc(&self, layout: Layout) -> Option<NonNull<u8>> {
+        self.0.lock().take_block(layout).ok()
     }
 
     /// Show memory usage in heap.
     pub fn show(&self) {
-        serial_println!("{:#?}", self.0);
+        serial_println!("{:#?}", *self.0.lock());
     }
 }
 
 impl Deref for LockedHeap {
-    type Target = Mutex<Heap>;
+    type Target = SpinLock<Heap>;
 
-    fn deref(&self) -> &Mutex<Heap> {
+    fn deref(&self) -> &SpinLock<Heap> {
         &self.0
     }
 }
 
 unsafe impl GlobalAlloc for LockedHeap {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.0
-            .lock()
-            .alloc(layout)
-            .ok()
-            .map_or(0 as *mut u8, |allocation| allocation.as_ptr())
+        let mut result = self.try_alloc(layout);
+        if let Some(grow) = self.1.r#try() {
+            // another thread may take the grown memory before the retry
+            while result.is_none() && grow(layout) {
+                result = self.try_alloc(layout);
+            }
+        }
+        if result.is_none() {
+            self.0.lock().failed += 1;
+        }
+        result.map_or(0 as *mut u8, |allocation| allocation.as_ptr())
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
@@ -220,6 +396,11 @@ unsafe impl GlobalAlloc for LockedHeap {
     }
 
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let resized = self.0.lock().realloc_in_place(NonNull::new_unchecked(ptr), layout, new_size);
+        if let Some(resized) = resized {
+            return resized.as_ptr();
+        }
+
         let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
         let new_ptr = unsafe { self.alloc(new_layout) };
         if !new_ptr.is_null() {
@@ -232,6 +413,20 @@ unsafe impl GlobalAlloc for LockedHeap {
     }
 }
 
+impl KernelAllocator for LockedHeap {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.0.lock().init(heap_start, heap_size);
+    }
+
+    fn stats(&self) -> HeapStats {
+        self.0.lock().stats()
+    }
+
+    fn dump(&self) {
+        self.show();
+    }
+}
+
 pub(crate) fn prev_power_of_two(num: usize) -> usize {
     1 << (8 * (size_of::<usize>()) - num.leading_zeros() as usize - 1)
 }
\ No newline at end of file
@@ -1,32 +1,49 @@
-use alloc::collections::BTreeSet;
 use core::cmp::min;
 use core::ops::Range;
 use core::ops::Deref;
-use spin::Mutex;
+use crate::allocator::buddy_system::bitmap::FreeBitmap;
 use crate::allocator::buddy_system::buddy_manager::prev_power_of_two;
+use crate::sync::SpinLock;
 
-/// A frame allocator that uses buddy system,
-/// requiring a global allocator.
-/// Create a frame allocator and add some frames to it:
+/// Orders of the blocks, the largest holds 2^31 frames
+const ORDERS: usize = 32;
+
+/// A frame allocator that uses buddy system.
+///
+/// Free blocks are only tracked in a bitmap with caller provided storage,
+/// so it never allocates from the heap, and the heap can grow by taking
+/// frames from it.
 pub struct FrameAllocator {
-    free_list: [BTreeSet<usize>; 32],
+    free: FreeBitmap,
+    /// Frame numbers below it can be added
+    capacity: usize,
+    /// Free blocks of each order
+    counts: [usize; ORDERS],
+    /// Index of the lowest block of each order that may be free
+    hints: [usize; ORDERS],
     allocated: usize,
     total: usize,
 }
 
 impl FrameAllocator {
-    /// Create an empty frame allocator
-    pub fn new() -> Self {
+    /// Create an empty frame allocator for the frame numbers [0, capacity),
+    /// `words` must hold at least `bitmap_words_from(capacity, 0)` entries
+    pub fn new(capacity: usize, words: &'static mut [usize]) -> Self {
         FrameAllocator {
-            free_list: Default::default(),
+            free: FreeBitmap::with_min_order(0, capacity, 0, words),
+            capacity,
+            counts: [0; ORDERS],
+            hints: [0; ORDERS],
             allocated: 0,
             total: 0,
         }
     }
 
-    /// Add a range of frame number [start, end) to the allocator
+    /// Add a range of frame number [start, end) to the allocator, frames
+    /// past the capacity are left out
     pub fn add_frame(&mut self, start: usize, end: usize) {
         assert!(start <= end);
+        let end = min(end, self.capacity);
 
         let mut total = 0;
         let mut current_start = start;
@@ -40,49 +57,56 @@ impl FrameAllocator {
             let size = min(lowbit, prev_power_of_two(end - current_start));
             total += size;
 
-            self.free_list[size.trailing_zeros() as usize].insert(current_start);
+            self.insert_block(size.trailing_zeros() as usize, current_start);
             current_start += size;
         }
 
         self.total += total;
     }
 
+    /// Count the frames [start, end), handed out before the allocator took
+    /// over, as allocated so that they can be given back with `dealloc`.
+    /// Frames past the capacity are left out.
+    pub fn add_allocated_frame(&mut self, start: usize, end: usize) {
+        assert!(start <= end);
+        let count = min(end, self.capacity).saturating_sub(start);
+        self.total += count;
+        self.allocated += count;
+    }
+
     /// Add a range of frame to the allocator
     pub fn insert(&mut self, range: Range<usize>) {
         self.add_frame(range.start, range.end);
     }
 
+    fn insert_block(&mut self, order: usize, block: usize) {
+        self.free.set(order, block);
+        self.counts[order] += 1;
+        self.hints[order] = min(self.hints[order], block >> order);
+    }
+
+    fn remove_block(&mut self, order: usize, block: usize) {
+        self.free.clear(order, block);
+        self.counts[order] -= 1;
+    }
+
     /// Alloc a range of frames from the allocator, return the first frame of the allocated range
     pub fn alloc(&mut self, count: usize) -> Option<usize> {
         let size = count.next_power_of_two();
         let class = size.trailing_zeros() as usize;
-        for i in class..self.free_list.len() {
-            // Find the first non-empty size class
-            if !self.free_list[i].is_empty() {
-                // Split buffers
-                for j in (class + 1..i + 1).rev() {
-                    if let Some(block_ref) = self.free_list[j].iter().next() {
-                        let block = *block_ref;
-                        self.free_list[j - 1].insert(block + (1 << (j - 1)));
-                        self.free_list[j - 1].insert(block);
-                        self.free_list[j].remove(&block);
-                    } else {
-                        return None;
-                    }
-                }
-
-                let result = self.free_list[class].iter().next().clone();
-                return if let Some(result_ref) = result {
-                    let result = *result_ref;
-                    self.free_list[class].remove(&result);
-                    self.allocated += size;
-                    Some(result)
-                } else {
-                    None
-                }
-            }
+        // Find the first non-empty size class
+        let order = (class..ORDERS).find(|&order| self.counts[order] > 0)?;
+        let block = self.free.first_free(order, self.hints[order])
+            .expect("free block count out of sync with the bitmap");
+        self.hints[order] = block >> order;
+        self.remove_block(order, block);
+
+        // Split buffers, keeping the lower half
+        for j in (class..order).rev() {
+            self.insert_block(j, block + (1 << j));
         }
-        None
+        self.allocated += size;
+        Some(block)
     }
 
     /// Dealloc a range of frames [frame, frame+count) from the frame allocator.
@@ -91,40 +115,49 @@ impl FrameAllocator {
         let size = count.next_power_of_two();
         let class = size.trailing_zeros() as usize;
 
-        // Merge free buddy lists
+        // Merge free buddies
         let mut current_ptr = frame;
         let mut current_class = class;
-        while current_class < self.free_list.len() {
+        while current_class + 1 < ORDERS {
             let buddy = current_ptr ^ (1 << current_class);
-            if self.free_list[current_class].remove(&buddy) == true {
-                // Free buddy found
-                current_ptr = min(current_ptr, buddy);
-                current_class += 1;
-            } else {
-                self.free_list[current_class].insert(current_ptr);
+            if buddy + (1 << current_class) > self.capacity || !self.free.test(current_class, buddy) {
                 break;
             }
+            self.remove_block(current_class, buddy);
+            current_ptr = min(current_ptr, buddy);
+            current_class += 1;
         }
+        self.insert_block(current_class, current_ptr);
 
         self.allocated -= size;
     }
+
+    /// Return the number of frames that are actually allocated
+    pub fn stats_alloc_frames(&self) -> usize {
+        self.allocated
+    }
+
+    /// Return the total number of frames in the allocator
+    pub fn stats_total_frames(&self) -> usize {
+        self.total
+    }
 }
 
 /// A locked version of `FrameAllocator`
 /// Create a locked frame allocator and add frames to it:
-pub struct LockedFrameAllocator(Mutex<FrameAllocator>);
+pub struct LockedFrameAllocator(SpinLock<FrameAllocator>);
 
 impl LockedFrameAllocator {
-    /// Creates an empty heap
-    pub fn new() -> LockedFrameAllocator {
-        LockedFrameAllocator(Mutex::new(FrameAllocator::new()))
+    /// Creates an empty frame allocator, see `FrameAllocator::new`
+    pub fn new(capacity: usize, words: &'static mut [usize]) -> LockedFrameAllocator {
+        LockedFrameAllocator(SpinLock::new(FrameAllocator::new(capacity, words)))
     }
 }
 
 impl Deref for LockedFrameAllocator {
-    type Target = Mutex<FrameAllocator>;
+    type Target = SpinLock<FrameAllocator>;
 
-    fn deref(&self) -> &Mutex<FrameAllocator> {
+    fn deref(&self) -> &SpinLock<FrameAllocator> {
         &self.0
     }
 }
\ No newline at end of file
@@ -0,0 +1,118 @@
+use core::{fmt, ptr};
+
+/// Links stored at the start of every free block
+#[repr(C)]
+struct Links {
+    next: *mut Links,
+    prev: *mut Links,
+}
+
+/// An intrusive doubly linked list of free blocks.
+/// Blocks must be at least two words large, any block in the list
+/// can be unlinked in constant time given its address.
+#[derive(Copy, Clone)]
+pub struct FreeList {
+    head: *mut Links,
+    len: usize,
+}
+
+unsafe impl Send for FreeList {}
+
+impl FreeList {
+    /// Create a new FreeList
+    pub const fn new() -> FreeList {
+        FreeList {
+            head: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    /// Return `true` if the list is empty
+    pub fn is_empty(&self) -> bool {
+        self.head.is_null()
+    }
+
+    /// Return the number of blocks in the list
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Push the block at `item` to the front of the list
+    pub unsafe fn push(&mut self, item: *mut usize) {
+        let node = item as *mut Links;
+        (*node).next = self.head;
+        (*node).prev = ptr::null_mut();
+        if !self.head.is_null() {
+            (*self.head).prev = node;
+        }
+        self.head = node;
+        self.len += 1;
+    }
+
+    /// Try to remove the first item in the list
+    pub fn pop(&mut self) -> Option<*mut usize> {
+        match self.is_empty() {
+            true => None,
+            false => {
+                let item = self.head as *mut usize;
+                unsafe { self.remove(item) };
+                Some(item)
+            }
+        }
+    }
+
+    /// Unlink the block at `item`, which must be in this list
+    pub unsafe fn remove(&mut self, item: *mut usize) {
+        let node = item as *mut Links;
+        let next = (*node).next;
+        let prev = (*node).prev;
+        if prev.is_null() {
+            self.head = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        self.len -= 1;
+    }
+
+    /// Return `true` if the block at `item` is in the list, walking the whole list
+    pub fn contains(&self, item: *mut usize) -> bool {
+        self.iter().any(|block| block == item)
+    }
+
+    /// Return an iterator over the items in the list
+    pub fn iter(&self) -> Iter {
+        Iter {
+            curr: self.head,
+            list: self,
+        }
+    }
+}
+
+impl fmt::Debug for FreeList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// An iterator over the free list
+pub struct Iter<'a> {
+    curr: *mut Links,
+    list: &'a FreeList,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = *mut usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.curr.is_null() {
+            None
+        } else {
+            let item = self.curr;
+            self.curr = unsafe { (*item).next };
+            Some(item as *mut usize)
+        }
+    }
+}
@@ -1,3 +1,7 @@
 pub mod buddy_manager;
 pub mod linked_list;
-pub mod frame;
\ No newline at end of file
+pub mod frame;
+pub mod free_list;
+pub mod bitmap;
+#[cfg(test)]
+pub mod bench;
\ No newline at end of file
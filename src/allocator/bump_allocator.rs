@@ -1,12 +1,19 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 use crate::allocator::alloc::{align_up, Locked};
+use crate::allocator::kernel_allocator::{count_free_region, HeapStats, KernelAllocator};
+use crate::serial_println;
 
 pub struct BumpAllocator {
     heap_start: usize,
     heap_end: usize,
     next: usize,
     allocations: usize,
+    user: usize,
+    peak: usize,
+    allocs: usize,
+    frees: usize,
+    failed: usize,
 }
 
 impl BumpAllocator {
@@ -17,6 +24,11 @@ impl BumpAllocator {
             heap_end: 0,
             next: 0,
             allocations: 0,
+            user: 0,
+            peak: 0,
+            allocs: 0,
+            frees: 0,
+            failed: 0,
         }
     }
 
@@ -38,24 +50,64 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         let alloc_start = align_up(bump.next, layout.align());
         let alloc_end = match alloc_start.checked_add(layout.size()) {
             Some(end) => end,
-            None => return ptr::null_mut(),
+            None => {
+                bump.failed += 1;
+                return ptr::null_mut();
+            }
         };
 
         if alloc_end > bump.heap_end {
+            bump.failed += 1;
             ptr::null_mut() // out of memory
         } else {
             bump.next = alloc_end;
             bump.allocations += 1;
+            bump.user += layout.size();
+            bump.allocs += 1;
+            bump.peak = bump.peak.max(alloc_end - bump.heap_start);
             alloc_start as *mut u8
         }
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc(&self, _ptr: *mut u8, layout: Layout) {
         let mut bump = self.lock(); // get a mutable reference
 
         bump.allocations -= 1;
+        bump.user -= layout.size();
+        bump.frees += 1;
         if bump.allocations == 0 {
             bump.next = bump.heap_start;
         }
     }
-}
\ No newline at end of file
+}
+
+impl KernelAllocator for Locked<BumpAllocator> {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.lock().init(heap_start, heap_size);
+    }
+
+    fn stats(&self) -> HeapStats {
+        let bump = self.lock();
+        let mut stats = HeapStats {
+            user: bump.user,
+            allocated: bump.next - bump.heap_start,
+            total: bump.heap_end - bump.heap_start,
+            peak: bump.peak,
+            allocs: bump.allocs,
+            frees: bump.frees,
+            failed: bump.failed,
+            ..HeapStats::default()
+        };
+        count_free_region(&mut stats, bump.heap_end - bump.next);
+        stats
+    }
+
+    fn dump(&self) {
+        let (next, allocations) = {
+            let bump = self.lock();
+            (bump.next, bump.allocations)
+        };
+        serial_println!("BumpAllocator {{ next: {:#x}, allocations: {} }}", next, allocations);
+        serial_println!("{}", self.stats());
+    }
+}
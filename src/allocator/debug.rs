@@ -0,0 +1,386 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::BACKEND;
+use crate::allocator::alloc::{heap_end, HEAP_START};
+use crate::allocator::addr_table::{AddrTable, Slot, TABLE_SIZE};
+use crate::allocator::kernel_allocator::{HeapStats, KernelAllocator};
+use crate::serial_println;
+use crate::sync::SpinLock;
+
+/// Guard bytes placed after every allocation, and at least before it
+pub const RED_ZONE: usize = 16;
+/// Pattern of the guard bytes
+pub const RED_ZONE_BYTE: u8 = 0xfd;
+/// Pattern written over fresh allocations
+pub const ALLOC_POISON: u8 = 0xcd;
+/// Pattern written over freed allocations, red zones included
+pub const FREE_POISON: u8 = 0xdd;
+/// Pattern of the guard bytes before allocations missing from the table
+pub const UNTRACKED_BYTE: u8 = 0xfe;
+
+const RECENT_FREES: usize = 256;
+/// Freed blocks held back from the backend, and the bytes they may take
+const QUARANTINE: usize = 64;
+const QUARANTINE_BYTES: usize = 64 * 1024;
+
+/// A misuse of the allocator found when freeing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// The address was freed before and not allocated again
+    DoubleFree { addr: usize, layout: Layout },
+    /// The address was never handed out
+    InvalidFree { addr: usize, layout: Layout },
+    /// The layout given to `dealloc` differs from the one allocated
+    LayoutMismatch { addr: usize, allocated: Layout, freed: Layout },
+    /// A guard byte was changed, `offset` is relative to the allocation start
+    RedZoneOverwrite { addr: usize, layout: Layout, offset: isize, value: u8 },
+    /// A freed block was written to while in quarantine, `offset` is
+    /// relative to the allocation start
+    UseAfterFree { addr: usize, layout: Layout, offset: isize, value: u8 },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::DoubleFree { addr, layout } => write!(
+                f, "double free of {:#x} ({} bytes, align {})",
+                addr, layout.size(), layout.align()
+            ),
+            Violation::InvalidFree { addr, layout } => write!(
+                f, "free of {:#x} ({} bytes, align {}) which was never allocated",
+                addr, layout.size(), layout.align()
+            ),
+            Violation::LayoutMismatch { addr, allocated, freed } => write!(
+                f, "layout mismatch freeing {:#x}: allocated {} bytes align {}, freed {} bytes align {}",
+                addr, allocated.size(), allocated.align(), freed.size(), freed.align()
+            ),
+            Violation::RedZoneOverwrite { addr, layout, offset, value } => write!(
+                f, "red zone overwrite at offset {} of {:#x} ({} bytes, align {}): found {:#04x}",
+                offset, addr, layout.size(), layout.align(), value
+            ),
+            Violation::UseAfterFree { addr, layout, offset, value } => write!(
+                f, "use after free at offset {} of {:#x} ({} bytes, align {}): found {:#04x}",
+                offset, addr, layout.size(), layout.align(), value
+            ),
+        }
+    }
+}
+
+/// An allocation in the ring of recent frees
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreedAllocation {
+    pub layout: Layout,
+    /// Whether the block is still in quarantine, not yet given back to
+    /// the backend
+    pub quarantined: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Record {
+    size: usize,
+    align: usize,
+}
+
+impl Record {
+    const EMPTY: Slot<Record> = Slot { addr: 0, value: Record { size: 0, align: 0 } };
+
+    fn layout(&self) -> Layout {
+        unsafe { Layout::from_size_align_unchecked(self.size, self.align) }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Freed {
+    addr: usize,
+    record: Record,
+}
+
+impl Freed {
+    const EMPTY: Freed = Freed { addr: 0, record: Record { size: 0, align: 0 } };
+}
+
+/// Live allocations in a table kept outside the heap, a ring of recent
+/// frees to tell double frees from invalid ones, and a ring of freed
+/// blocks kept poisoned before they go back to the backend.
+struct AllocationTable {
+    live: AddrTable<Record>,
+    freed: [Freed; RECENT_FREES],
+    next_freed: usize,
+    quarantine: [Freed; QUARANTINE],
+    /// Index of the oldest quarantined block
+    quarantine_head: usize,
+    quarantine_len: usize,
+    /// Backend bytes taken by the quarantined blocks
+    quarantine_bytes: usize,
+}
+
+impl AllocationTable {
+    const fn new() -> Self {
+        AllocationTable {
+            live: AddrTable::new([Record::EMPTY; TABLE_SIZE]),
+            freed: [Freed::EMPTY; RECENT_FREES],
+            next_freed: 0,
+            quarantine: [Freed::EMPTY; QUARANTINE],
+            quarantine_head: 0,
+            quarantine_len: 0,
+            quarantine_bytes: 0,
+        }
+    }
+
+    /// Move `addr` from the live allocations to the recent frees and the
+    /// quarantine, returning the oldest quarantined block if there was no room
+    fn remove(&mut self, addr: usize, record: Record) -> Option<Freed> {
+        self.live.remove(addr);
+        let freed = Freed { addr, record };
+        self.freed[self.next_freed] = freed;
+        self.next_freed = (self.next_freed + 1) % RECENT_FREES;
+
+        let evicted = if self.quarantine_len == QUARANTINE { self.pop_oldest() } else { None };
+        self.quarantine[(self.quarantine_head + self.quarantine_len) % QUARANTINE] = freed;
+        self.quarantine_len += 1;
+        self.quarantine_bytes += DebugAllocator::outer_layout(&record.layout()).size();
+        evicted
+    }
+
+    /// Take the oldest quarantined block while the quarantine holds too many bytes
+    fn evict(&mut self) -> Option<Freed> {
+        if self.quarantine_bytes <= QUARANTINE_BYTES {
+            return None;
+        }
+        self.pop_oldest()
+    }
+
+    fn pop_oldest(&mut self) -> Option<Freed> {
+        if self.quarantine_len == 0 {
+            return None;
+        }
+        let freed = self.quarantine[self.quarantine_head];
+        self.quarantine_head = (self.quarantine_head + 1) % QUARANTINE;
+        self.quarantine_len -= 1;
+        self.quarantine_bytes -= DebugAllocator::outer_layout(&freed.record.layout()).size();
+        Some(freed)
+    }
+
+    fn quarantined(&self) -> impl Iterator<Item = &Freed> {
+        (0..self.quarantine_len).map(move |i| &self.quarantine[(self.quarantine_head + i) % QUARANTINE])
+    }
+
+    /// Return the latest free of `addr` still in the ring
+    fn recent_free(&self, addr: usize) -> Option<&Freed> {
+        (1..=RECENT_FREES)
+            .map(|age| &self.freed[(self.next_freed + RECENT_FREES - age) % RECENT_FREES])
+            .find(|freed| freed.addr == addr)
+    }
+
+    fn recently_freed(&self, addr: usize) -> bool {
+        self.recent_free(addr).is_some()
+    }
+}
+
+/// Allocator wrapper catching heap misuse.
+///
+/// Wraps the backend picked by the `alloc-*` feature: every allocation gets
+/// red zones on both sides and its layout recorded, fresh and freed memory is
+/// poisoned, and `dealloc` panics with a report on double free, invalid free,
+/// layout mismatch or red zone overwrite. Freed blocks stay in quarantine for
+/// a while and are checked for writes before going back to the backend.
+///
+/// Allocations made while the table is full are handed out untracked, with
+/// `UNTRACKED_BYTE` guards in front, and only get the red zone checks.
+pub struct DebugAllocator {
+    table: SpinLock<AllocationTable>,
+    /// Live allocations that did not fit in the table
+    untracked: AtomicUsize,
+}
+
+impl DebugAllocator {
+    pub const fn new() -> Self {
+        DebugAllocator {
+            table: SpinLock::new(AllocationTable::new()),
+            untracked: AtomicUsize::new(0),
+        }
+    }
+
+    /// Return the number of live allocations
+    pub fn live_allocations(&self) -> usize {
+        self.table.lock().live.len()
+    }
+
+    /// Return the number of live allocations missing from the table
+    pub fn untracked_allocations(&self) -> usize {
+        self.untracked.load(Ordering::SeqCst)
+    }
+
+    /// Return how `ptr` was freed, if it is among the recent frees
+    pub fn recent_free(&self, ptr: *mut u8) -> Option<FreedAllocation> {
+        let table = self.table.lock();
+        table.recent_free(ptr as usize).map(|freed| FreedAllocation {
+            layout: freed.record.layout(),
+            quarantined: table.quarantined().any(|held| held.addr == freed.addr),
+        })
+    }
+
+    /// Check what freeing `ptr` with `layout` would do, without freeing it
+    pub fn check_free(&self, ptr: *mut u8, layout: Layout) -> Result<(), Violation> {
+        let table = self.table.lock();
+        Self::find_violation(&table, self.untracked_allocations(), ptr as usize, layout).map(|_| ())
+    }
+
+    /// Check that no quarantined block was written to since it was freed
+    pub fn check_quarantine(&self) -> Result<(), Violation> {
+        let table = self.table.lock();
+        table.quarantined().try_for_each(Self::check_poison)
+    }
+
+    /// Bytes before the user pointer, keeping it aligned
+    fn front(layout: &Layout) -> usize {
+        layout.align().max(RED_ZONE)
+    }
+
+    /// Layout of the block taken from the backend
+    fn outer_layout(layout: &Layout) -> Layout {
+        let size = Self::front(layout) + layout.size() + RED_ZONE;
+        unsafe { Layout::from_size_align_unchecked(size, layout.align()) }
+    }
+
+    /// Whether the front guard bytes of `addr` mark an untracked allocation.
+    /// Addresses whose guards would lie outside the mapped heap are not read.
+    fn marked_untracked(addr: usize, layout: &Layout) -> bool {
+        let front = Self::front(layout);
+        let in_heap = addr.checked_sub(front).map_or(false, |start| start >= HEAP_START) && addr <= heap_end();
+        in_heap && (1..=front).all(|back| unsafe { *(addr as *const u8).sub(back) } == UNTRACKED_BYTE)
+    }
+
+    /// Return whether `addr` is in the table, or the violation freeing it would be
+    fn find_violation(
+        table: &AllocationTable,
+        untracked: usize,
+        addr: usize,
+        layout: Layout,
+    ) -> Result<bool, Violation> {
+        let (allocated, front_byte) = match table.live.get(addr) {
+            Some(record) => (record.layout(), RED_ZONE_BYTE),
+            // freeing poisons the guards, so a freed block never looks untracked
+            None if untracked != 0 && Self::marked_untracked(addr, &layout) => (layout, UNTRACKED_BYTE),
+            None if table.recently_freed(addr) => return Err(Violation::DoubleFree { addr, layout }),
+            None => return Err(Violation::InvalidFree { addr, layout }),
+        };
+
+        if allocated != layout {
+            return Err(Violation::LayoutMismatch { addr, allocated, freed: layout });
+        }
+
+        let front = Self::front(&layout) as isize;
+        let guards = (-front..0).map(|offset| (offset, front_byte))
+            .chain((layout.size() as isize..(layout.size() + RED_ZONE) as isize).map(|offset| (offset, RED_ZONE_BYTE)));
+        for (offset, expected) in guards {
+            let value = unsafe { *(addr as *const u8).offset(offset) };
+            if value != expected {
+                return Err(Violation::RedZoneOverwrite { addr, layout, offset, value });
+            }
+        }
+        Ok(front_byte == RED_ZONE_BYTE)
+    }
+
+    /// Check that a quarantined block, red zones included, still holds `FREE_POISON`
+    fn check_poison(freed: &Freed) -> Result<(), Violation> {
+        let (addr, layout) = (freed.addr, freed.record.layout());
+        let front = Self::front(&layout) as isize;
+        for offset in -front..(layout.size() + RED_ZONE) as isize {
+            let value = unsafe { *(addr as *const u8).offset(offset) };
+            if value != FREE_POISON {
+                return Err(Violation::UseAfterFree { addr, layout, offset, value });
+            }
+        }
+        Ok(())
+    }
+
+    /// Give a block leaving quarantine back to the backend
+    unsafe fn release(&self, freed: Freed) {
+        if let Err(violation) = Self::check_poison(&freed) {
+            panic!("debug allocator: {}", violation);
+        }
+        let layout = freed.record.layout();
+        BACKEND.dealloc((freed.addr as *mut u8).sub(Self::front(&layout)), Self::outer_layout(&layout));
+    }
+}
+
+unsafe impl GlobalAlloc for DebugAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // the backend may allocate itself, don't hold the table lock
+        let outer = BACKEND.alloc(Self::outer_layout(&layout));
+        if outer.is_null() {
+            return outer;
+        }
+
+        let front = Self::front(&layout);
+        let user = outer.add(front);
+        ptr::write_bytes(outer, RED_ZONE_BYTE, front);
+        ptr::write_bytes(user, ALLOC_POISON, layout.size());
+        ptr::write_bytes(user.add(layout.size()), RED_ZONE_BYTE, RED_ZONE);
+
+        let tracked = self.table.lock().live.insert(user as usize, Record {
+            size: layout.size(),
+            align: layout.align(),
+        });
+        if !tracked {
+            ptr::write_bytes(outer, UNTRACKED_BYTE, front);
+            self.untracked.fetch_add(1, Ordering::SeqCst);
+        }
+        user
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let outer = Self::outer_layout(&layout);
+        let evicted = {
+            let mut table = self.table.lock();
+            match Self::find_violation(&table, self.untracked_allocations(), ptr as usize, layout) {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.untracked.fetch_sub(1, Ordering::SeqCst);
+                }
+                Err(violation) => {
+                    drop(table);
+                    panic!("debug allocator: {}", violation);
+                }
+            }
+            ptr::write_bytes(ptr.sub(Self::front(&layout)), FREE_POISON, outer.size());
+            table.remove(ptr as usize, Record { size: layout.size(), align: layout.align() })
+        };
+
+        // the backend may allocate itself, release without the table lock
+        if let Some(freed) = evicted {
+            self.release(freed);
+        }
+        loop {
+            let evicted = self.table.lock().evict();
+            match evicted {
+                Some(freed) => self.release(freed),
+                None => break,
+            }
+        }
+    }
+}
+
+impl KernelAllocator for DebugAllocator {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        BACKEND.init(heap_start, heap_size);
+    }
+
+    /// Statistics of the backend, including the red zones. Quarantined
+    /// blocks count as freed but their bytes as allocated.
+    fn stats(&self) -> HeapStats {
+        let quarantined = self.table.lock().quarantine_len;
+        let mut stats = BACKEND.stats();
+        stats.frees += quarantined;
+        stats
+    }
+
+    fn dump(&self) {
+        serial_println!("DebugAllocator {{ live: {}, untracked: {} }}",
+                        self.live_allocations(), self.untracked_allocations());
+        BACKEND.dump();
+    }
+}
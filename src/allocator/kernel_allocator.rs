@@ -0,0 +1,83 @@
+use core::alloc::GlobalAlloc;
+use core::fmt;
+use crate::{println, serial_println};
+
+/// Snapshot of the usage and fragmentation of a kernel heap
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    /// Bytes requested by users
+    pub user: usize,
+    /// Bytes actually taken from the heap, including rounding
+    pub allocated: usize,
+    /// Bytes managed by the heap
+    pub total: usize,
+    /// Number of free blocks of each order (size `1 << order`).
+    /// Free regions that are not a power of two are counted in the order below their size.
+    pub free_blocks: [usize; 32],
+    /// Size of the largest free block
+    pub largest_free: usize,
+    /// Highest value `allocated` has reached
+    pub peak: usize,
+    /// Number of successful allocations
+    pub allocs: usize,
+    /// Number of deallocations
+    pub frees: usize,
+    /// Number of allocations that returned null
+    pub failed: usize,
+    /// Number of times the heap was extended
+    pub growths: usize,
+}
+
+impl HeapStats {
+    /// Return the number of free bytes
+    pub fn free(&self) -> usize {
+        self.total - self.allocated
+    }
+
+    /// Print the report on the VGA console and over serial
+    pub fn print(&self) {
+        println!("{}", self);
+        serial_println!("{}", self);
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "heap: {} / {} bytes allocated ({} requested, peak {})",
+                 self.allocated, self.total, self.user, self.peak)?;
+        writeln!(f, "      {} allocs, {} frees, {} failed, {} growths",
+                 self.allocs, self.frees, self.failed, self.growths)?;
+        write!(f, "      largest free block {} bytes, free blocks:", self.largest_free)?;
+        for (order, count) in self.free_blocks.iter().enumerate() {
+            if *count != 0 {
+                write!(f, " {}x{}", count, 1usize << order)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Count a free region of `size` bytes in `stats`
+pub(crate) fn count_free_region(stats: &mut HeapStats, size: usize) {
+    if size == 0 {
+        return;
+    }
+    let order = (8 * core::mem::size_of::<usize>() - 1) - size.leading_zeros() as usize;
+    stats.free_blocks[order.min(31)] += 1;
+    stats.largest_free = stats.largest_free.max(size);
+}
+
+/// Common interface of the heaps that can back `#[global_allocator]`
+pub trait KernelAllocator: GlobalAlloc + Sync {
+    /// Hand the memory [heap_start, heap_start + heap_size) to the allocator.
+    ///
+    /// This method is unsafe because the caller must ensure that the given
+    /// memory range is mapped and unused. It must be called only once.
+    unsafe fn init(&self, heap_start: usize, heap_size: usize);
+
+    /// Return a snapshot of the heap usage
+    fn stats(&self) -> HeapStats;
+
+    /// Print the allocator state over serial
+    fn dump(&self);
+}
@@ -2,6 +2,8 @@ use core::{mem, ptr, cmp};
 use core::alloc::{Layout, GlobalAlloc};
 use super::alloc::align_up;
 use crate::allocator::alloc::Locked;
+use crate::allocator::kernel_allocator::{count_free_region, HeapStats, KernelAllocator};
+use crate::serial_println;
 use crate::println;
 use core::ptr::{null, null_mut};
 use alloc::alloc::dealloc;
@@ -27,17 +29,34 @@ impl Node {
 
 pub struct Allocator {
     head: Node,
+
+    // statistics
+    user: usize,
+    allocated: usize,
+    total: usize,
+    peak: usize,
+    allocs: usize,
+    frees: usize,
+    failed: usize,
 }
 
 impl Allocator {
     pub const fn new() -> Self {
         Self {
-            head: Node::new(0)
+            head: Node::new(0),
+            user: 0,
+            allocated: 0,
+            total: 0,
+            peak: 0,
+            allocs: 0,
+            frees: 0,
+            failed: 0,
         }
     }
 
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.add_free_region(heap_start, heap_size);
+        self.total += heap_size;
     }
 
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
@@ -89,6 +108,68 @@ impl Allocator {
         Ok(alloc_start)
     }
 
+    /// Unlink the free region starting exactly at `addr`, returning its size
+    fn take_region_at(&mut self, addr: usize) -> Option<usize> {
+        let mut current = &mut self.head;
+
+        while let Some(ref mut region) = current.next {
+            if region.start_address() == addr {
+                let next = region.next.take();
+                let size = region.size;
+                current.next = next;
+                return Some(size);
+            } else {
+                current = current.next.as_mut().unwrap();
+            }
+        }
+        None
+    }
+
+    /// Resize the allocation at `ptr` from `size` to `new_size` adjusted bytes
+    /// without moving it, using the free region right after it.
+    /// Returns `false` if the allocation can't be resized in place.
+    unsafe fn resize_in_place(&mut self, ptr: usize, size: usize, new_size: usize) -> bool {
+        if new_size == size {
+            return true;
+        }
+
+        let end = ptr + size;
+        let new_end = ptr + new_size;
+        if new_size < size {
+            // give the tail back, merged with a free region following it
+            let tail = size - new_size + self.take_region_at(end).unwrap_or(0);
+            if tail < mem::size_of::<Node>() {
+                // no region followed, the tail alone can't hold a Node
+                return false;
+            }
+            self.add_free_region(new_end, tail);
+            self.allocated -= size - new_size;
+            return true;
+        }
+
+        match self.take_region_at(end) {
+            Some(free) if free >= new_size - size => {
+                let excess = free - (new_size - size);
+                if excess > 0 && excess < mem::size_of::<Node>() {
+                    // the rest could not hold a Node
+                    self.add_free_region(end, free);
+                    return false;
+                }
+                if excess > 0 {
+                    self.add_free_region(new_end, excess);
+                }
+                self.allocated += new_size - size;
+                self.peak = cmp::max(self.peak, self.allocated);
+                true
+            }
+            Some(free) => {
+                self.add_free_region(end, free);
+                false
+            }
+            None => false,
+        }
+    }
+
     fn size_align(layout: Layout) -> (usize, usize) {
         let layout = layout
             .align_to(mem::align_of::<Node>())
@@ -111,8 +192,13 @@ unsafe impl GlobalAlloc for Locked<Allocator> {
             if excess_size > 0 {
                 allocator.add_free_region(alloc_end, excess_size);
             }
+            allocator.user += layout.size();
+            allocator.allocated += size;
+            allocator.peak = cmp::max(allocator.peak, allocator.allocated);
+            allocator.allocs += 1;
             alloc_start as *mut u8
         } else {
+            allocator.failed += 1;
             ptr::null_mut()
         }
     }
@@ -121,11 +207,25 @@ unsafe impl GlobalAlloc for Locked<Allocator> {
         // perform layout adjustments
         let (size, _) = Allocator::size_align(layout);
 
-        self.lock().add_free_region(ptr as usize, size)
+        let mut allocator = self.lock();
+        allocator.add_free_region(ptr as usize, size);
+        allocator.user -= layout.size();
+        allocator.allocated -= size;
+        allocator.frees += 1;
     }
 
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
         let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        {
+            let (size, _) = Allocator::size_align(layout);
+            let (new_adjusted, _) = Allocator::size_align(new_layout);
+            let mut allocator = self.lock();
+            if allocator.resize_in_place(ptr as usize, size, new_adjusted) {
+                allocator.user = allocator.user - layout.size() + new_size;
+                return ptr;
+            }
+        }
+
         let new_ptr = unsafe { self.alloc(new_layout) };
         if !new_ptr.is_null() {
             unsafe {
@@ -137,3 +237,33 @@ unsafe impl GlobalAlloc for Locked<Allocator> {
     }
 }
 
+impl KernelAllocator for Locked<Allocator> {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.lock().init(heap_start, heap_size);
+    }
+
+    fn stats(&self) -> HeapStats {
+        let allocator = self.lock();
+        let mut stats = HeapStats {
+            user: allocator.user,
+            allocated: allocator.allocated,
+            total: allocator.total,
+            peak: allocator.peak,
+            allocs: allocator.allocs,
+            frees: allocator.frees,
+            failed: allocator.failed,
+            ..HeapStats::default()
+        };
+
+        let mut current = &allocator.head;
+        while let Some(region) = current.next.as_deref() {
+            count_free_region(&mut stats, region.size);
+            current = region;
+        }
+        stats
+    }
+
+    fn dump(&self) {
+        serial_println!("{}", self.stats());
+    }
+}
@@ -1,4 +1,11 @@
 pub mod list;
 pub mod alloc;
 pub mod bump_allocator;
-pub mod buddy_system;
\ No newline at end of file
+pub mod buddy_system;
+pub mod slab;
+pub mod kernel_allocator;
+pub mod addr_table;
+#[cfg(feature = "alloc-debug")]
+pub mod debug;
+#[cfg(feature = "alloc-track")]
+pub mod tracker;
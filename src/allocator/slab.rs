@@ -0,0 +1,377 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::cmp::{max, min};
+use core::mem::{self, size_of};
+use core::ptr::{self, NonNull};
+use crate::allocator::buddy_system::buddy_manager::LockedHeap;
+use crate::allocator::buddy_system::linked_list;
+use crate::allocator::kernel_allocator::{HeapStats, KernelAllocator};
+use crate::serial_println;
+use crate::sync::SpinLock;
+
+/// Object sizes served by the slab caches, smaller requests are rounded up
+pub const SLAB_SIZES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Smallest block the caches take from the backing heap
+pub const SLAB_PAGE_SIZE: usize = 4096;
+
+/// Objects a slab holds at least, larger objects get larger slabs
+const MIN_SLAB_OBJECTS: usize = 16;
+
+/// Statistics of a single slab cache
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub object_size: usize,
+    pub slabs: usize,
+    pub in_use: usize,
+    pub free: usize,
+    pub allocs: usize,
+    pub frees: usize,
+    /// Bytes requested by the users of the objects in use
+    pub user: usize,
+}
+
+/// Header at the start of each slab, in the room of its first objects
+struct Slab {
+    object_size: usize,
+    free_list: linked_list::LinkedList,
+    in_use: usize,
+    /// Neighbours in the list of slabs with free objects
+    prev: *mut Slab,
+    next: *mut Slab,
+}
+
+/// A cache of equally sized objects carved out of heap blocks.
+///
+/// Each slab keeps its own free objects and use count. Slabs with free
+/// objects are listed, most recently freed to first, so a freed object is
+/// handed out again next. At most one slab without objects in use is kept
+/// as a spare, an older spare is given back to the heap.
+pub struct SlabCache {
+    object_size: usize,
+    /// Slabs with free objects
+    partial: *mut Slab,
+    /// The slab without objects in use, kept in `partial`
+    spare: *mut Slab,
+
+    // statistics
+    slabs: usize,
+    in_use: usize,
+    free: usize,
+    allocs: usize,
+    frees: usize,
+    user: usize,
+}
+
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    /// Create an empty cache for objects of `object_size` bytes
+    pub const fn new(object_size: usize) -> Self {
+        SlabCache {
+            object_size,
+            partial: ptr::null_mut(),
+            spare: ptr::null_mut(),
+            slabs: 0,
+            in_use: 0,
+            free: 0,
+            allocs: 0,
+            frees: 0,
+            user: 0,
+        }
+    }
+
+    /// Return the size of the slabs, which are aligned to it
+    pub const fn slab_size(&self) -> usize {
+        let size = self.object_size * MIN_SLAB_OBJECTS;
+        if size > SLAB_PAGE_SIZE { size } else { SLAB_PAGE_SIZE }
+    }
+
+    /// Return the number of objects taken by the slab header
+    fn header_objects(&self) -> usize {
+        (size_of::<Slab>() + self.object_size - 1) / self.object_size
+    }
+
+    fn slab_of(&self, ptr: NonNull<u8>) -> *mut Slab {
+        (ptr.as_ptr() as usize & !(self.slab_size() - 1)) as *mut Slab
+    }
+
+    unsafe fn link(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn unlink(&mut self, slab: *mut Slab) {
+        let (prev, next) = ((*slab).prev, (*slab).next);
+        if prev.is_null() {
+            self.partial = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+    }
+
+    /// Carve the block [start, start + slab_size()) into free objects.
+    /// Only called when no slab has free objects, so it becomes the spare.
+    pub unsafe fn add_slab(&mut self, start: usize) {
+        let slab = start as *mut Slab;
+        slab.write(Slab {
+            object_size: self.object_size,
+            free_list: linked_list::LinkedList::new(),
+            in_use: 0,
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+        });
+        let count = self.slab_size() / self.object_size;
+        // push in reverse so objects are handed out in address order
+        for i in (self.header_objects()..count).rev() {
+            (*slab).free_list.push((start + i * self.object_size) as *mut usize);
+        }
+        self.link(slab);
+        self.spare = slab;
+        self.slabs += 1;
+        self.free += count - self.header_objects();
+    }
+
+    /// Take a free object from the cache for `size` requested bytes
+    pub fn alloc(&mut self, size: usize) -> Option<NonNull<u8>> {
+        let slab = self.partial;
+        if slab.is_null() {
+            return None;
+        }
+        let object = unsafe {
+            let object = (*slab).free_list.pop().expect("full slab in the partial list");
+            if (*slab).in_use == 0 {
+                self.spare = ptr::null_mut();
+            }
+            (*slab).in_use += 1;
+            if (*slab).free_list.is_empty() {
+                self.unlink(slab);
+            }
+            object
+        };
+        self.free -= 1;
+        self.in_use += 1;
+        self.allocs += 1;
+        self.user += size;
+        NonNull::new(object as *mut u8)
+    }
+
+    /// Return an object of `size` requested bytes to the cache. Returns the
+    /// old spare slab to give back to the heap if this one became the spare.
+    pub unsafe fn dealloc(&mut self, ptr: NonNull<u8>, size: usize) -> Option<NonNull<u8>> {
+        let slab = self.slab_of(ptr);
+        if !(*slab).free_list.is_empty() {
+            self.unlink(slab);
+        }
+        self.link(slab);
+        (*slab).free_list.push(ptr.as_ptr() as *mut usize);
+        (*slab).in_use -= 1;
+        self.free += 1;
+        self.in_use -= 1;
+        self.frees += 1;
+        // the size freed may not be the size allocated
+        self.user = self.user.saturating_sub(size);
+        if (*slab).in_use != 0 {
+            return None;
+        }
+
+        let old = mem::replace(&mut self.spare, slab);
+        if old.is_null() {
+            return None;
+        }
+        self.unlink(old);
+        self.slabs -= 1;
+        self.free -= self.slab_size() / self.object_size - self.header_objects();
+        NonNull::new(old as *mut u8)
+    }
+
+    /// Account for an object in use resized from `old_size` to `new_size`
+    /// requested bytes
+    pub fn resize(&mut self, old_size: usize, new_size: usize) {
+        self.user = self.user - old_size + new_size;
+    }
+
+    /// Return a snapshot of the cache statistics
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            object_size: self.object_size,
+            slabs: self.slabs,
+            in_use: self.in_use,
+            free: self.free,
+            allocs: self.allocs,
+            frees: self.frees,
+            user: self.user,
+        }
+    }
+}
+
+/// Slab front end for a buddy heap
+/// Small allocations are served by per-size caches, the rest goes to the heap.
+pub struct SlabAllocator {
+    caches: [SpinLock<SlabCache>; 9],
+    heap: &'static LockedHeap,
+}
+
+impl SlabAllocator {
+    /// Create a slab allocator taking its pages from `heap`
+    pub const fn new(heap: &'static LockedHeap) -> Self {
+        SlabAllocator {
+            caches: [
+                SpinLock::new(SlabCache::new(8)),
+                SpinLock::new(SlabCache::new(16)),
+                SpinLock::new(SlabCache::new(32)),
+                SpinLock::new(SlabCache::new(64)),
+                SpinLock::new(SlabCache::new(128)),
+                SpinLock::new(SlabCache::new(256)),
+                SpinLock::new(SlabCache::new(512)),
+                SpinLock::new(SlabCache::new(1024)),
+                SpinLock::new(SlabCache::new(2048)),
+            ],
+            heap,
+        }
+    }
+
+    /// Return the heap backing the caches
+    pub fn heap(&self) -> &'static LockedHeap {
+        self.heap
+    }
+
+    /// Return the statistics of every cache, smallest objects first
+    pub fn cache_stats(&self) -> [SlabStats; 9] {
+        let mut stats = [self.caches[0].lock().stats(); 9];
+        for (i, cache) in self.caches.iter().enumerate().skip(1) {
+            stats[i] = cache.lock().stats();
+        }
+        stats
+    }
+
+    /// Show memory usage of the caches and of the heap behind them.
+    pub fn show(&self) {
+        for stats in self.cache_stats().iter() {
+            serial_println!("{:?}", stats);
+        }
+        self.heap.show();
+    }
+
+    /// Return the index of the cache serving `layout`, if any
+    fn cache_index(layout: &Layout) -> Option<usize> {
+        let size = max(max(layout.size(), layout.align()), SLAB_SIZES[0]).next_power_of_two();
+        if size <= SLAB_SIZES[SLAB_SIZES.len() - 1] {
+            Some((size.trailing_zeros() - SLAB_SIZES[0].trailing_zeros()) as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Layout of the slabs of cache `index` in the heap
+    fn slab_layout(&self, index: usize) -> Layout {
+        let size = SlabCache::new(SLAB_SIZES[index]).slab_size();
+        unsafe { Layout::from_size_align_unchecked(size, size) }
+    }
+
+    unsafe fn alloc_from_cache(&self, index: usize, size: usize) -> *mut u8 {
+        if let Some(object) = self.caches[index].lock().alloc(size) {
+            return object.as_ptr();
+        }
+
+        // the cache lock must not be held here: the heap may grow and
+        // allocate bookkeeping memory through this allocator
+        let slab = self.heap.alloc(self.slab_layout(index));
+        if slab.is_null() {
+            return ptr::null_mut();
+        }
+
+        let mut cache = self.caches[index].lock();
+        if let Some(object) = cache.alloc(size) {
+            // another context refilled the cache meanwhile
+            drop(cache);
+            self.heap.dealloc(slab, self.slab_layout(index));
+            return object.as_ptr();
+        }
+        cache.add_slab(slab as usize);
+        cache.alloc(size).map_or(ptr::null_mut(), |object| object.as_ptr())
+    }
+
+    /// Return an object to the cache owning its slab, which is found from
+    /// the cache `index` serving the layout it is freed with
+    unsafe fn dealloc_to_cache(&self, index: usize, ptr: *mut u8, size: usize) {
+        let slab = SlabCache::new(SLAB_SIZES[index]).slab_of(NonNull::new_unchecked(ptr));
+        let index = Self::cache_index(&Layout::from_size_align_unchecked((*slab).object_size, 1))
+            .expect("slab header without a cache");
+        let empty = self.caches[index].lock().dealloc(NonNull::new_unchecked(ptr), size);
+        if let Some(slab) = empty {
+            self.heap.dealloc(slab.as_ptr(), self.slab_layout(index));
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match Self::cache_index(&layout) {
+            Some(index) => self.alloc_from_cache(index, layout.size()),
+            None => self.heap.alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match Self::cache_index(&layout) {
+            Some(index) => self.dealloc_to_cache(index, ptr, layout.size()),
+            None => self.heap.dealloc(ptr, layout),
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let index = Self::cache_index(&layout);
+        let new_index = Self::cache_index(&new_layout);
+        if let (Some(index), Some(new_index)) = (index, new_index) {
+            if index == new_index {
+                // still fits the same object size
+                self.caches[index].lock().resize(layout.size(), new_size);
+                return ptr;
+            }
+        }
+        if index.is_none() && new_index.is_none() {
+            // the heap may resize the block in place
+            return self.heap.realloc(ptr, layout, new_size);
+        }
+
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+impl KernelAllocator for SlabAllocator {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.heap.init(heap_start, heap_size);
+    }
+
+    /// Heap statistics with the cache traffic added to the allocation counts,
+    /// and the bytes requested from the caches counted instead of their slabs.
+    /// The bytes counted as allocated include the slabs owned by the caches.
+    fn stats(&self) -> HeapStats {
+        let mut stats = self.heap.stats();
+        // the two snapshots are not taken at once, so a slab may be counted
+        // by only one of them
+        for (index, cache) in self.cache_stats().iter().enumerate() {
+            stats.allocs += cache.allocs;
+            stats.frees += cache.frees;
+            stats.user = (stats.user + cache.user).saturating_sub(cache.slabs * self.slab_layout(index).size());
+        }
+        stats
+    }
+
+    fn dump(&self) {
+        self.show();
+    }
+}
@@ -0,0 +1,222 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use crate::UNTRACKED;
+use crate::allocator::addr_table::{AddrTable, Slot, TABLE_SIZE};
+use crate::allocator::kernel_allocator::{HeapStats, KernelAllocator};
+use crate::time;
+use crate::serial_println;
+use crate::sync::SpinLock;
+
+/// Return addresses recorded for each allocation, innermost first
+pub const TRACE_DEPTH: usize = 4;
+/// Frames between `TrackingAllocator::alloc` and the allocating code
+/// that are not worth recording (the `__rg_alloc` shim)
+const SKIP_FRAMES: usize = 1;
+/// Call sites shown by `dump_live_allocations`, the rest are summed up
+const MAX_SITES: usize = 64;
+
+/// A live allocation
+#[derive(Debug, Clone, Copy)]
+pub struct LiveAllocation {
+    pub size: usize,
+    /// Allocation sequence number, see `TrackingAllocator::mark`
+    pub seq: u64,
+    /// Timer tick at allocation time
+    pub tick: u64,
+    /// Return addresses of the allocating call chain, 0 past the end
+    pub trace: [usize; TRACE_DEPTH],
+}
+
+impl LiveAllocation {
+    const EMPTY: Slot<LiveAllocation> = Slot {
+        addr: 0,
+        value: LiveAllocation { size: 0, seq: 0, tick: 0, trace: [0; TRACE_DEPTH] },
+    };
+}
+
+/// Live allocations of a call site
+#[derive(Clone, Copy)]
+struct Site {
+    trace: [usize; TRACE_DEPTH],
+    count: usize,
+    bytes: usize,
+    oldest_tick: u64,
+}
+
+/// Walk the frame pointer chain of the caller.
+///
+/// Relies on the kernel being built with frame pointers, which `build.rs`
+/// checks for `alloc-track`. The walk stops at the first frame pointer that
+/// does not go up the stack.
+#[inline(always)]
+fn backtrace() -> [usize; TRACE_DEPTH] {
+    let mut trace = [0; TRACE_DEPTH];
+    let mut rbp: usize;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+
+    let mut depth = 0;
+    while rbp != 0 && rbp % 8 == 0 && depth < SKIP_FRAMES + TRACE_DEPTH {
+        let frame = rbp as *const usize;
+        let (next, ret) = unsafe { (*frame, *frame.add(1)) };
+        if depth >= SKIP_FRAMES {
+            trace[depth - SKIP_FRAMES] = ret;
+        }
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+        depth += 1;
+    }
+    trace
+}
+
+/// Allocator wrapper recording live allocations.
+///
+/// Every allocation of the wrapped allocator is recorded with its size,
+/// calling code and timer tick in a table kept outside the heap, so leaks
+/// can be counted and reported by call site.
+pub struct TrackingAllocator {
+    table: SpinLock<AddrTable<LiveAllocation>>,
+    next_seq: AtomicU64,
+    /// Live allocations that did not fit in the table
+    untracked: AtomicUsize,
+}
+
+impl TrackingAllocator {
+    pub const fn new() -> Self {
+        TrackingAllocator {
+            table: SpinLock::new(AddrTable::new([LiveAllocation::EMPTY; TABLE_SIZE])),
+            next_seq: AtomicU64::new(0),
+            untracked: AtomicUsize::new(0),
+        }
+    }
+
+    /// Return the sequence number the next allocation will get.
+    /// Allocations still live later with a sequence number at or above
+    /// the mark were made after it.
+    pub fn mark(&self) -> u64 {
+        self.next_seq.load(Ordering::SeqCst)
+    }
+
+    /// Return the number of live allocations
+    pub fn live_allocations(&self) -> usize {
+        self.table.lock().len()
+    }
+
+    /// Return the bytes still allocated by allocations made since `mark`
+    pub fn live_bytes_since(&self, mark: u64) -> usize {
+        self.table.lock()
+            .iter()
+            .filter(|(_, live)| live.seq >= mark)
+            .map(|(_, live)| live.size)
+            .sum()
+    }
+
+    /// Return the record of the live allocation at `ptr`
+    pub fn lookup(&self, ptr: *const u8) -> Option<LiveAllocation> {
+        self.table.lock().get(ptr as usize).copied()
+    }
+
+    /// Print the live allocations over serial, grouped by call site,
+    /// largest sites first
+    pub fn dump_live_allocations(&self) {
+        let mut sites = [Site { trace: [0; TRACE_DEPTH], count: 0, bytes: 0, oldest_tick: 0 }; MAX_SITES];
+        let mut used = 0;
+        let (mut other_count, mut other_bytes) = (0, 0);
+        let (mut count, mut bytes) = (0, 0);
+
+        for (_, live) in self.table.lock().iter() {
+            count += 1;
+            bytes += live.size;
+            match sites[..used].iter_mut().find(|site| site.trace == live.trace) {
+                Some(site) => {
+                    site.count += 1;
+                    site.bytes += live.size;
+                    site.oldest_tick = site.oldest_tick.min(live.tick);
+                }
+                None if used < MAX_SITES => {
+                    sites[used] = Site { trace: live.trace, count: 1, bytes: live.size, oldest_tick: live.tick };
+                    used += 1;
+                }
+                None => {
+                    other_count += 1;
+                    other_bytes += live.size;
+                }
+            }
+        }
+
+        sites[..used].sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes));
+        serial_println!("live allocations: {} ({} bytes) from {} call sites, {} untracked",
+                        count, bytes, used, self.untracked.load(Ordering::Relaxed));
+        for site in sites[..used].iter() {
+            serial_println!("  {} bytes in {} allocations, oldest at tick {}, trace {:#x?}",
+                            site.bytes, site.count, site.oldest_tick, site.trace);
+        }
+        if other_count != 0 {
+            serial_println!("  {} bytes in {} allocations from other call sites", other_bytes, other_count);
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let trace = backtrace();
+        // the wrapped allocator may allocate itself, don't hold the table lock
+        let ptr = UNTRACKED.alloc(layout);
+        if ptr.is_null() {
+            return ptr;
+        }
+
+        let live = LiveAllocation {
+            size: layout.size(),
+            seq: self.next_seq.fetch_add(1, Ordering::SeqCst),
+            tick: time::ticks(),
+            trace,
+        };
+        if !self.table.lock().insert(ptr as usize, live) {
+            self.untracked.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if self.table.lock().remove(ptr as usize).is_none() {
+            let _ = self.untracked.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| count.checked_sub(1));
+        }
+        UNTRACKED.dealloc(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // taken out first, once the block moved another thread may get `ptr`
+        let live = self.table.lock().remove(ptr as usize);
+        let new_ptr = UNTRACKED.realloc(ptr, layout, new_size);
+        let (kept, size) = if new_ptr.is_null() { (ptr, layout.size()) } else { (new_ptr, new_size) };
+
+        // the allocation keeps its call site and sequence number, an
+        // untracked one stays untracked
+        if let Some(mut live) = live {
+            live.size = size;
+            if !self.table.lock().insert(kept as usize, live) {
+                self.untracked.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        new_ptr
+    }
+}
+
+impl KernelAllocator for TrackingAllocator {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        UNTRACKED.init(heap_start, heap_size);
+    }
+
+    fn stats(&self) -> HeapStats {
+        UNTRACKED.stats()
+    }
+
+    fn dump(&self) {
+        self.dump_live_allocations();
+        UNTRACKED.dump();
+    }
+}
@@ -0,0 +1,168 @@
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use x86_64::PhysAddr;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+use crate::memory::vmalloc;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS: u64 = 0x000f_ffff_ffff_f000;
+
+/// Where the I/O APIC of PC chipsets is, unless the firmware says otherwise
+pub const IOAPIC_DEFAULT_BASE: u64 = 0xfec0_0000;
+
+/// Vector the local APIC uses for spurious interrupts, they need no EOI
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// local APIC registers, offsets from its base
+const LAPIC_ID: usize = 0x20;
+const LAPIC_VERSION: usize = 0x30;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+// I/O APIC registers, accessed through a select and a window register
+const IOAPIC_SELECT: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+pub const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// Virtual addresses of the APIC registers, 0 until `init`
+static LAPIC: AtomicU64 = AtomicU64::new(0);
+static IOAPIC: AtomicU64 = AtomicU64::new(0);
+/// Global system interrupt of the first input of the I/O APIC
+static IOAPIC_GSI_BASE: AtomicU32 = AtomicU32::new(0);
+
+/// Return whether the CPU has a local APIC
+pub fn is_supported() -> bool {
+    unsafe { __cpuid(1).edx & (1 << 9) != 0 }
+}
+
+/// Return whether interrupts are delivered through the APICs
+pub fn is_active() -> bool {
+    LAPIC.load(Ordering::Relaxed) != 0
+}
+
+/// Enable the local APIC and map the I/O APIC at `ioapic_base`, whose
+/// inputs start at `gsi_base`, with every input masked. Returns `false` if
+/// there is no APIC.
+///
+/// This function is unsafe because the caller must mask the 8259 PIC and
+/// route the interrupts it needs.
+pub unsafe fn init(ioapic_base: PhysAddr, gsi_base: u32) -> bool {
+    if !is_supported() {
+        return false;
+    }
+
+    let mut msr = Msr::new(IA32_APIC_BASE);
+    let base = msr.read();
+
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let lapic = vmalloc::ioremap(PhysAddr::new(base & APIC_BASE_ADDRESS), 4096, flags, "local apic");
+    let ioapic = vmalloc::ioremap(ioapic_base, 4096, flags, "io apic");
+    let (lapic, ioapic) = match (lapic, ioapic) {
+        (Ok(lapic), Ok(ioapic)) => (lapic, ioapic),
+        (Ok(mapped), Err(_)) | (Err(_), Ok(mapped)) => {
+            vmalloc::vfree(mapped);
+            return false;
+        }
+        (Err(_), Err(_)) => return false,
+    };
+    msr.write(base | APIC_BASE_ENABLE);
+    IOAPIC.store(ioapic.as_u64(), Ordering::Relaxed);
+    IOAPIC_GSI_BASE.store(gsi_base, Ordering::Relaxed);
+    for gsi in gsi_base..gsi_base + ioapic_inputs() {
+        mask_irq(gsi);
+    }
+
+    LAPIC.store(lapic.as_u64(), Ordering::Relaxed);
+    lapic_write(LAPIC_TASK_PRIORITY, 0);
+    lapic_write(LAPIC_SPURIOUS, LAPIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+    true
+}
+
+unsafe fn lapic_read(register: usize) -> u32 {
+    ptr::read_volatile((LAPIC.load(Ordering::Relaxed) as usize + register) as *const u32)
+}
+
+unsafe fn lapic_write(register: usize, value: u32) {
+    ptr::write_volatile((LAPIC.load(Ordering::Relaxed) as usize + register) as *mut u32, value);
+}
+
+unsafe fn ioapic_read(register: u32) -> u32 {
+    let base = IOAPIC.load(Ordering::Relaxed) as usize;
+    ptr::write_volatile((base + IOAPIC_SELECT) as *mut u32, register);
+    ptr::read_volatile((base + IOAPIC_WINDOW) as *const u32)
+}
+
+unsafe fn ioapic_write(register: u32, value: u32) {
+    let base = IOAPIC.load(Ordering::Relaxed) as usize;
+    ptr::write_volatile((base + IOAPIC_SELECT) as *mut u32, register);
+    ptr::write_volatile((base + IOAPIC_WINDOW) as *mut u32, value);
+}
+
+/// Return the id of the local APIC of this CPU
+pub fn local_apic_id() -> u8 {
+    unsafe { (lapic_read(LAPIC_ID) >> 24) as u8 }
+}
+
+/// Return the version register of the local APIC
+pub fn local_apic_version() -> u32 {
+    unsafe { lapic_read(LAPIC_VERSION) }
+}
+
+/// Signal the end of the interrupt being handled
+pub fn end_of_interrupt() {
+    unsafe { lapic_write(LAPIC_EOI, 0) };
+}
+
+/// Return the number of inputs of the I/O APIC
+pub fn ioapic_inputs() -> u32 {
+    unsafe { ((ioapic_read(IOAPIC_VERSION) >> 16) & 0xff) + 1 }
+}
+
+/// Return the first register of the redirection entry of `gsi`
+fn redirection_register(gsi: u32) -> u32 {
+    IOAPIC_REDIRECTION + 2 * (gsi - IOAPIC_GSI_BASE.load(Ordering::Relaxed))
+}
+
+/// Return the redirection entry of global system interrupt `gsi`
+pub fn redirection(gsi: u32) -> u64 {
+    let register = redirection_register(gsi);
+    unsafe {
+        let low = ioapic_read(register) as u64;
+        let high = ioapic_read(register + 1) as u64;
+        high << 32 | low
+    }
+}
+
+fn set_redirection(gsi: u32, entry: u64) {
+    let register = redirection_register(gsi);
+    unsafe {
+        ioapic_write(register + 1, (entry >> 32) as u32);
+        ioapic_write(register, entry as u32);
+    }
+}
+
+/// Deliver global system interrupt `gsi` as `vector` to this CPU
+pub fn route_irq(gsi: u32, vector: u8, active_low: bool, level_triggered: bool) {
+    let mut entry = (local_apic_id() as u64) << 56 | vector as u64;
+    if active_low {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if level_triggered {
+        entry |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    set_redirection(gsi, entry);
+}
+
+/// Stop delivering input `gsi`
+pub fn mask_irq(gsi: u32) {
+    set_redirection(gsi, REDIRECTION_MASKED);
+}
@@ -0,0 +1,386 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode};
+use x86_64::VirtAddr;
+use crate::gdt::DOUBLE_FAULT_IST_INDEX;
+use crate::memory::{stack, vma};
+use crate::sync::SpinLock;
+use crate::{println, serial_println};
+
+/// Number of vectors reserved for CPU exceptions
+pub const EXCEPTION_COUNT: usize = 32;
+
+pub const DIVIDE_ERROR: u8 = 0;
+pub const DEBUG: u8 = 1;
+pub const BREAKPOINT: u8 = 3;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const PAGE_FAULT: u8 = 14;
+
+/// Mnemonic, name and whether the CPU pushes an error code, by vector
+const EXCEPTIONS: [(&str, &str, bool); EXCEPTION_COUNT] = [
+    ("#DE", "DIVIDE ERROR", false),
+    ("#DB", "DEBUG", false),
+    ("NMI", "NON-MASKABLE INTERRUPT", false),
+    ("#BP", "BREAKPOINT", false),
+    ("#OF", "OVERFLOW", false),
+    ("#BR", "BOUND RANGE EXCEEDED", false),
+    ("#UD", "INVALID OPCODE", false),
+    ("#NM", "DEVICE NOT AVAILABLE", false),
+    ("#DF", "DOUBLE FAULT", true),
+    ("#CSO", "COPROCESSOR SEGMENT OVERRUN", false),
+    ("#TS", "INVALID TSS", true),
+    ("#NP", "SEGMENT NOT PRESENT", true),
+    ("#SS", "STACK SEGMENT FAULT", true),
+    ("#GP", "GENERAL PROTECTION FAULT", true),
+    ("#PF", "PAGE FAULT", true),
+    ("-", "RESERVED", false),
+    ("#MF", "X87 FLOATING POINT", false),
+    ("#AC", "ALIGNMENT CHECK", true),
+    ("#MC", "MACHINE CHECK", false),
+    ("#XM", "SIMD FLOATING POINT", false),
+    ("#VE", "VIRTUALIZATION", false),
+    ("#CP", "CONTROL PROTECTION", true),
+    ("-", "RESERVED", false),
+    ("-", "RESERVED", false),
+    ("-", "RESERVED", false),
+    ("-", "RESERVED", false),
+    ("-", "RESERVED", false),
+    ("-", "RESERVED", false),
+    ("#HV", "HYPERVISOR INJECTION", false),
+    ("#VC", "VMM COMMUNICATION", true),
+    ("#SX", "SECURITY", true),
+    ("-", "RESERVED", false),
+];
+
+// One 16-byte stub per vector pushes a zero error code when the CPU does
+// not, then the vector number, so every exception reaches
+// `exception_dispatch` with the same `ExceptionFrame` layout.
+global_asm!(r#"
+.intel_syntax noprefix
+
+.macro exception_stub vector, error_code
+    .balign 16
+    .if \error_code == 0
+    push 0
+    .endif
+    push \vector
+    jmp exception_common
+.endm
+
+.global exception_stubs
+.balign 16
+exception_stubs:
+    exception_stub 0, 0
+    exception_stub 1, 0
+    exception_stub 2, 0
+    exception_stub 3, 0
+    exception_stub 4, 0
+    exception_stub 5, 0
+    exception_stub 6, 0
+    exception_stub 7, 0
+    exception_stub 8, 1
+    exception_stub 9, 0
+    exception_stub 10, 1
+    exception_stub 11, 1
+    exception_stub 12, 1
+    exception_stub 13, 1
+    exception_stub 14, 1
+    exception_stub 15, 0
+    exception_stub 16, 0
+    exception_stub 17, 1
+    exception_stub 18, 0
+    exception_stub 19, 0
+    exception_stub 20, 0
+    exception_stub 21, 1
+    exception_stub 22, 0
+    exception_stub 23, 0
+    exception_stub 24, 0
+    exception_stub 25, 0
+    exception_stub 26, 0
+    exception_stub 27, 0
+    exception_stub 28, 0
+    exception_stub 29, 1
+    exception_stub 30, 1
+    exception_stub 31, 0
+
+exception_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    cld
+    call exception_dispatch
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 16
+    iretq
+
+.att_syntax
+"#);
+
+extern "C" {
+    fn exception_stubs();
+}
+
+const STUB_SIZE: usize = 16;
+
+/// General purpose registers at the time of the exception
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+/// Stack contents built by the exception stubs and the CPU.
+/// Changes made by a handler are restored on return.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExceptionFrame {
+    pub registers: Registers,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Everything known about an exception
+#[derive(Debug, Clone, Copy)]
+pub struct CrashReport {
+    pub frame: ExceptionFrame,
+    pub cr2: u64,
+    pub cr3: u64,
+    /// Name of the kernel stack whose guard page was hit
+    pub stack_overflow: Option<&'static str>,
+}
+
+impl CrashReport {
+    /// Capture the control registers along with `frame`
+    pub fn capture(frame: &ExceptionFrame) -> Self {
+        let cr2 = Cr2::read();
+        // an overflow faults on the guard page, and as the CPU can't push the
+        // page fault frame on the full stack, usually ends in a double fault
+        let stack_overflow = match frame.vector as u8 {
+            PAGE_FAULT | DOUBLE_FAULT => stack::guard_hit(cr2).map(|stack| stack.name),
+            _ => None,
+        };
+        CrashReport {
+            frame: *frame,
+            cr2: cr2.as_u64(),
+            cr3: Cr3::read().0.start_address().as_u64(),
+            stack_overflow,
+        }
+    }
+
+    pub fn vector(&self) -> u8 {
+        self.frame.vector as u8
+    }
+
+    /// Return the short name of the exception, like `#GP`
+    pub fn mnemonic(&self) -> &'static str {
+        EXCEPTIONS[self.vector() as usize].0
+    }
+
+    pub fn name(&self) -> &'static str {
+        EXCEPTIONS[self.vector() as usize].1
+    }
+
+    /// Return whether the CPU pushed an error code for this exception
+    pub fn has_error_code(&self) -> bool {
+        EXCEPTIONS[self.vector() as usize].2
+    }
+
+    /// Print the report on the VGA console and over serial
+    pub fn print(&self) {
+        println!("{}", self);
+        serial_println!("{}", self);
+    }
+
+    fn fmt_error_code(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.frame.error_code;
+        match self.vector() {
+            PAGE_FAULT => write!(f, "{:#x} {:?}", code, PageFaultErrorCode::from_bits_truncate(code)),
+            // the error code of these is a segment selector index
+            10..=13 if code != 0 => {
+                let table = match (code >> 1) & 0b11 {
+                    0 => "GDT",
+                    2 => "LDT",
+                    _ => "IDT",
+                };
+                write!(f, "{:#x} {}[{}]", code, table, (code >> 3) & 0x1fff)?;
+                if code & 1 != 0 {
+                    write!(f, " external")?;
+                }
+                Ok(())
+            }
+            _ => write!(f, "{:#x}", code),
+        }
+    }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frame = &self.frame;
+        let r = &frame.registers;
+        writeln!(f, "EXCEPTION: {} {} (vector {})", self.mnemonic(), self.name(), frame.vector)?;
+        if let Some(stack) = self.stack_overflow {
+            writeln!(f, "stack overflow on stack {}", stack)?;
+        }
+        if self.has_error_code() {
+            write!(f, "error code: ")?;
+            self.fmt_error_code(f)?;
+            writeln!(f)?;
+        }
+        writeln!(f, "rip {:#018x} cs {:#06x} rflags {:#010x}", frame.rip, frame.cs, frame.rflags)?;
+        writeln!(f, "rsp {:#018x} ss {:#06x}", frame.rsp, frame.ss)?;
+        writeln!(f, "cr2 {:#018x} cr3 {:#018x}", self.cr2, self.cr3)?;
+        writeln!(f, "rax {:#018x} rbx {:#018x} rcx {:#018x}", r.rax, r.rbx, r.rcx)?;
+        writeln!(f, "rdx {:#018x} rsi {:#018x} rdi {:#018x}", r.rdx, r.rsi, r.rdi)?;
+        writeln!(f, "rbp {:#018x} r8  {:#018x} r9  {:#018x}", r.rbp, r.r8, r.r9)?;
+        writeln!(f, "r10 {:#018x} r11 {:#018x} r12 {:#018x}", r.r10, r.r11, r.r12)?;
+        write!(f, "r13 {:#018x} r14 {:#018x} r15 {:#018x}", r.r13, r.r14, r.r15)
+    }
+}
+
+/// Point the exception entries of `idt` at the stubs.
+///
+/// Only the entries public in `InterruptDescriptorTable` are set. Vectors 9,
+/// 15, 21 to 29 and 31 stay without a handler, raising one of them is
+/// reported as #NP with the IDT entry in the error code.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    // the stubs take the frame the CPU pushes, as the handler types expect
+    macro_rules! set_stubs {
+        ($($entry:ident: $vector:expr),* $(,)?) => {$(
+            idt.$entry.set_handler_fn(unsafe { core::mem::transmute(stub_address($vector)) });
+        )*};
+    }
+    set_stubs!(
+        divide_error: 0, debug: 1, non_maskable_interrupt: 2, breakpoint: 3,
+        overflow: 4, bound_range_exceeded: 5, invalid_opcode: 6, device_not_available: 7,
+        invalid_tss: 10, segment_not_present: 11, stack_segment_fault: 12,
+        general_protection_fault: 13, page_fault: 14, x87_floating_point: 16,
+        alignment_check: 17, machine_check: 18, simd_floating_point: 19,
+        virtualization: 20, security_exception: 30,
+    );
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(core::mem::transmute(stub_address(DOUBLE_FAULT)))
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+    }
+}
+
+/// Return the address of the stub of `vector`
+fn stub_address(vector: u8) -> usize {
+    exception_stubs as usize + vector as usize * STUB_SIZE
+}
+
+const NOT_EXPECTED: u64 = u64::MAX;
+
+/// Vector `catch_exception` waits for
+static EXPECTED: AtomicU64 = AtomicU64::new(NOT_EXPECTED);
+/// Where `catch_exception` resumes
+static RECOVERY_RIP: AtomicU64 = AtomicU64::new(0);
+static RECOVERY_RSP: AtomicU64 = AtomicU64::new(0);
+static CAUGHT: SpinLock<Option<CrashReport>> = SpinLock::new(None);
+
+/// Call `trigger`, expecting it to raise exception `vector`.
+///
+/// On that exception `trigger` is abandoned and the report is returned,
+/// `None` is returned if `trigger` returns normally. Any other exception
+/// is handled as usual.
+pub fn catch_exception(vector: u8, trigger: extern "C" fn()) -> Option<CrashReport> {
+    CAUGHT.lock().take();
+    EXPECTED.store(vector as u64, Ordering::SeqCst);
+    unsafe {
+        asm!(
+            "push rbx",
+            "push rbp",
+            "lea rax, [rip + 2f]",
+            "mov [rdi], rax",
+            "mov [rsi], rsp",
+            "call rdx",
+            "2:",
+            "pop rbp",
+            "pop rbx",
+            inout("rdi") &RECOVERY_RIP as *const AtomicU64 => _,
+            inout("rsi") &RECOVERY_RSP as *const AtomicU64 => _,
+            inout("rdx") trigger => _,
+            out("rax") _, out("rcx") _, out("r8") _, out("r9") _, out("r10") _,
+            out("r11") _, out("r12") _, out("r13") _, out("r14") _, out("r15") _,
+        );
+    }
+    EXPECTED.store(NOT_EXPECTED, Ordering::SeqCst);
+    CAUGHT.lock().take()
+}
+
+/// Common handler of all exceptions, called by the stubs
+#[no_mangle]
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    let report = CrashReport::capture(frame);
+
+    if frame.vector == PAGE_FAULT as u64 {
+        let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+        if vma::handle_page_fault(VirtAddr::new(report.cr2), error_code) {
+            return;
+        }
+    }
+
+    if EXPECTED.compare_exchange(frame.vector, NOT_EXPECTED, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+        *CAUGHT.lock() = Some(report);
+        frame.rip = RECOVERY_RIP.load(Ordering::SeqCst);
+        frame.rsp = RECOVERY_RSP.load(Ordering::SeqCst);
+        return;
+    }
+
+    report.print();
+    match frame.vector as u8 {
+        // traps, execution can go on
+        DEBUG | BREAKPOINT => {}
+        _ => match report.stack_overflow {
+            Some(stack) => panic!("stack overflow on stack {}", stack),
+            None => panic!("EXCEPTION: {} {}", report.mnemonic(), report.name()),
+        },
+    }
+}
@@ -1,30 +1,30 @@
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
+use x86_64::instructions::interrupts::without_interrupts;
 use lazy_static::lazy_static;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+/// Task state segment, its interrupt stack table is updated once guarded
+/// stacks can be allocated, see `memory::stack::init`
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// Double fault stack used until the guarded one is allocated
+fn boot_double_fault_stack() -> VirtAddr {
+    const STACK_SIZE: usize = 4096 * 5;
+    static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+    let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+    let stack_end = stack_start + STACK_SIZE;
+    stack_end
 }
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &TSS }));
         (gdt, Selectors { code_selector, tss_selector })
     };
 }
@@ -38,9 +38,22 @@ pub fn gdt_init(){
     use x86_64::instructions::segmentation::set_cs;
     use x86_64::instructions::tables::load_tss;
 
+    unsafe {
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = boot_double_fault_stack();
+    }
     GDT.0.load();
     unsafe {
         set_cs(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
-}
\ No newline at end of file
+}
+
+/// Make the CPU switch to `top` for the interrupts using stack table entry `index`.
+///
+/// This function is unsafe because the caller must guarantee that the stack
+/// stays mapped and unused by anything else.
+pub unsafe fn set_interrupt_stack(index: u16, top: VirtAddr) {
+    without_interrupts(|| {
+        TSS.interrupt_stack_table[index as usize] = top;
+    });
+}
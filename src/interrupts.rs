@@ -1,42 +1,54 @@
-use super::gdt::DOUBLE_FAULT_IST_INDEX;
-use crate::println;
-use crate::print;
+use crate::acpi::{self, madt::InterruptOverride};
+use crate::apic;
+use crate::exceptions;
+use crate::keyboard;
+use crate::serial;
+use crate::sync::IrqMutex;
+use crate::thread;
+use crate::time;
 use crate::vga::buffer::CONSOLE;
 use x86_64::structures::idt::*;
-use x86_64::registers::control::Cr2;
 use lazy_static::lazy_static;
 use spin;
-use spin::Mutex;
 use pic8259_simple::ChainedPics;
-use pc_keyboard::*;
+use x86_64::PhysAddr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub const PIC1_OFFSET: u8 = 32;
 pub const PIC2_OFFSET: u8 = PIC1_OFFSET + 8;
 
+const TIMER_IRQ: u8 = 0;
+const KEYBOARD_IRQ: u8 = 1;
+/// Input of the master PIC the slave PIC is chained to
+const CASCADE_IRQ: u8 = 2;
+pub const COM1_IRQ: u8 = 4;
+pub const RTC_IRQ: u8 = 8;
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        unsafe {
-            idt.double_fault
-            .set_handler_fn(double_fault_handler)
-            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
-        }
+        exceptions::install(&mut idt);
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial.as_usize()].set_handler_fn(serial_interrupt_handler);
+        idt[InterruptIndex::Rtc.as_usize()].set_handler_fn(rtc_interrupt_handler);
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
 
-pub static PICS: Mutex<ChainedPics> =
-    Mutex::new(unsafe{ ChainedPics::new(PIC1_OFFSET, PIC2_OFFSET)});
+pub static PICS: IrqMutex<ChainedPics> =
+    IrqMutex::new(unsafe{ ChainedPics::new(PIC1_OFFSET, PIC2_OFFSET)});
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC1_OFFSET,
-    Keyboard
+    Keyboard,
+    Serial = PIC1_OFFSET + COM1_IRQ,
+    Rtc = PIC2_OFFSET,
 }
 
 
@@ -50,62 +62,146 @@ impl InterruptIndex {
     }
 }
 
+/// Depth of the interrupt handlers running, they only nest through exceptions
+static HANDLER_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Marks the code of an interrupt handler until dropped
+struct HandlerContext;
+
+impl HandlerContext {
+    fn enter() -> Self {
+        HANDLER_DEPTH.fetch_add(1, Ordering::Relaxed);
+        HandlerContext
+    }
+}
+
+impl Drop for HandlerContext {
+    fn drop(&mut self) {
+        HANDLER_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Return whether the caller runs in an interrupt handler rather than for
+/// the thread it interrupted
+pub fn in_interrupt() -> bool {
+    HANDLER_DEPTH.load(Ordering::Relaxed) != 0
+}
+
 pub fn init_idt(){
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame)
-{
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+/// Move interrupt delivery from the 8259 PIC to the local and I/O APIC.
+///
+/// The I/O APIC and the wiring of the ISA interrupts are taken from the
+/// ACPI MADT when `acpi::init` found one. Returns `false` and keeps the PIC
+/// if there is no APIC. Needs `vmalloc` to map the APIC registers.
+pub fn enable_apic() -> bool {
+    let madt = acpi::tables().and_then(|acpi| acpi.madt.as_ref());
+    let (ioapic, gsi_base) = match madt.and_then(|madt| madt.io_apic_for(0)) {
+        Some(ioapic) => (ioapic.address, ioapic.gsi_base),
+        None => (PhysAddr::new(apic::IOAPIC_DEFAULT_BASE), 0),
+    };
+
+    without_interrupts(|| {
+        if !unsafe { apic::init(ioapic, gsi_base) } {
+            return false;
+        }
+        disable_pic();
+        let isa = [
+            (TIMER_IRQ, InterruptIndex::Timer),
+            (KEYBOARD_IRQ, InterruptIndex::Keyboard),
+            (COM1_IRQ, InterruptIndex::Serial),
+            (RTC_IRQ, InterruptIndex::Rtc),
+        ];
+        for &(irq, index) in isa.iter() {
+            let route = match madt {
+                Some(madt) => madt.isa_irq(irq),
+                // PC chipsets wire the timer to input 2
+                None if irq == TIMER_IRQ => InterruptOverride { gsi: 2, ..InterruptOverride::identity(irq) },
+                None => InterruptOverride::identity(irq),
+            };
+            apic::route_irq(route.gsi, index.as_u8(), route.active_low(), route.level_triggered());
+        }
+        true
+    })
 }
 
-extern "x86-interrupt" fn double_fault_handler(stack_frame: &mut InterruptStackFrame, _error_code: u64) -> !
-{
-    println!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame)
+/// Mask every input of both PICs, they keep the offsets set by `initialize`
+/// so a spurious interrupt can't be taken for an exception
+fn disable_pic() {
+    unsafe {
+        Port::<u8>::new(0x21).write(0xff);
+        Port::<u8>::new(0xa1).write(0xff);
+    }
 }
 
-extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut InterruptStackFrame, error_code: PageFaultErrorCode) {
-    println!("Accessed Address in CR2: {:?}", Cr2::read());
-    println!("The CR2 register is automatically set by the CPU on a page fault and contains the accessed virtual address that caused the page fault");
-    println!("Error Code: {:?}", error_code);
-    println!("EXCEPTION: PAGE FAULT\n{:#?}", stack_frame);
-    loop {
-        x86_64::instructions::hlt();
+/// Unmask input `irq` of the PIC, along with the cascade input for the
+/// inputs of the slave. Does nothing once the APIC delivers interrupts,
+/// `enable_apic` routed the inputs there.
+pub fn unmask_pic_irq(irq: u8) {
+    if apic::is_active() {
+        return;
+    }
+    without_interrupts(|| unsafe {
+        let mut master = Port::<u8>::new(0x21);
+        let master_irq = if irq >= 8 {
+            let mut slave = Port::<u8>::new(0xa1);
+            let mask = slave.read();
+            slave.write(mask & !(1 << (irq - 8)));
+            CASCADE_IRQ
+        } else {
+            irq
+        };
+        let mask = master.read();
+        master.write(mask & !(1 << master_irq));
+    });
+}
+
+/// Acknowledge interrupt `index` to the controller that delivered it
+fn end_of_interrupt(index: InterruptIndex) {
+    if apic::is_active() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) };
     }
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame)
 {
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    {
+        let _context = HandlerContext::enter();
+        end_of_interrupt(InterruptIndex::Timer);
+        time::tick();
     }
+    // the thread switched to must not run in the handler context
+    thread::preempt();
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame)
 {
-    use x86_64::instructions::port::Port;
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1,
-                HandleControl::Ignore)
-            );
-    }
-
-    let mut keyboard = KEYBOARD.lock();
+    let _context = HandlerContext::enter();
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
+    keyboard::handle_byte(scancode);
 
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
-    }
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
 
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: &mut InterruptStackFrame)
+{
+    let _context = HandlerContext::enter();
+    serial::handle_interrupt();
+    end_of_interrupt(InterruptIndex::Serial);
+}
+
+extern "x86-interrupt" fn rtc_interrupt_handler(_stack_frame: &mut InterruptStackFrame)
+{
+    let _context = HandlerContext::enter();
+    time::rtc::handle_interrupt();
+    end_of_interrupt(InterruptIndex::Rtc);
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame)
+{
 }
\ No newline at end of file
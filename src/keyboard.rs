@@ -0,0 +1,271 @@
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU16, AtomicU8, Ordering};
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
+use pc_keyboard::{
+    layouts, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, KeyboardLayout,
+    Modifiers, ScancodeSet1,
+};
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::instructions::port::Port;
+use crate::sync::SpinLock;
+use crate::task::queue::{self, ByteQueue};
+
+/// Scancodes the interrupt handler can queue before they are dropped
+pub const QUEUE_SIZE: usize = queue::QUEUE_SIZE;
+
+static QUEUE: ByteQueue = ByteQueue::new();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Key layouts the decoder can switch between
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Layout {
+    Us,
+    Uk,
+    Dvorak,
+    Azerty,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 4] = [Layout::Us, Layout::Uk, Layout::Dvorak, Layout::Azerty];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::Dvorak => "dvorak",
+            Layout::Azerty => "azerty",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL.iter().find(|layout| layout.name() == name).copied()
+    }
+}
+
+static LAYOUT: AtomicU8 = AtomicU8::new(Layout::Us as u8);
+
+/// Return the layout keys are decoded with
+pub fn layout() -> Layout {
+    Layout::ALL[LAYOUT.load(Ordering::Relaxed) as usize]
+}
+
+/// Decode the next keys with `layout`, modifiers held stay held
+pub fn set_layout(layout: Layout) {
+    LAYOUT.store(layout as u8, Ordering::Relaxed);
+}
+
+/// Forwards to the `pc_keyboard` layout picked by `set_layout`, whose
+/// layouts are types chosen at build time
+pub struct RuntimeLayout;
+
+impl KeyboardLayout for RuntimeLayout {
+    fn map_keycode(keycode: KeyCode, modifiers: &Modifiers, handle_ctrl: HandleControl) -> DecodedKey {
+        match layout() {
+            Layout::Us => layouts::Us104Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Uk => layouts::Uk105Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Dvorak => layouts::Dvorak104Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Azerty => layouts::Azerty::map_keycode(keycode, modifiers, handle_ctrl),
+        }
+    }
+}
+
+/// State of the lock keys, shown by the keyboard LEDs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockState {
+    pub caps: bool,
+    pub num: bool,
+    pub scroll: bool,
+}
+
+impl LockState {
+    /// Num lock starts on, like in `pc_keyboard`
+    const INITIAL: LockState = LockState { caps: false, num: true, scroll: false };
+
+    /// Return the argument of the set LEDs command
+    fn leds(&self) -> u8 {
+        (self.scroll as u8) | (self.num as u8) << 1 | (self.caps as u8) << 2
+    }
+
+    /// Toggle the lock of `event` if it presses a lock key
+    fn update(&mut self, event: &KeyEvent) -> bool {
+        if event.state != KeyState::Down {
+            return false;
+        }
+        let lock = match event.code {
+            KeyCode::CapsLock => &mut self.caps,
+            KeyCode::NumpadLock => &mut self.num,
+            KeyCode::ScrollLock => &mut self.scroll,
+            _ => return false,
+        };
+        *lock = !*lock;
+        true
+    }
+}
+
+struct Decoder {
+    keyboard: Keyboard<RuntimeLayout, ScancodeSet1>,
+    locks: LockState,
+}
+
+lazy_static! {
+    /// Decoding state, its lock also makes its holder the only queue reader.
+    /// Ctrl with a letter gives the control characters U+0001 to U+001A.
+    static ref DECODER: SpinLock<Decoder> = SpinLock::new(Decoder {
+        keyboard: Keyboard::new(RuntimeLayout, ScancodeSet1, HandleControl::MapLettersToUnicode),
+        locks: LockState::INITIAL,
+    });
+}
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const INPUT_BUFFER_FULL: u8 = 1 << 1;
+const SET_LEDS: u8 = 0xed;
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+/// `PENDING_LEDS` value when no LED change waits for an ACK
+const NO_LEDS: u16 = u16::MAX;
+
+/// LED byte to send once the keyboard acknowledges the set LEDs command
+static PENDING_LEDS: AtomicU16 = AtomicU16::new(NO_LEDS);
+
+/// Write `byte` to the keyboard once the controller can take it
+fn write_data(byte: u8) {
+    let mut status = Port::<u8>::new(STATUS_PORT);
+    for _ in 0..10_000 {
+        if unsafe { status.read() } & INPUT_BUFFER_FULL == 0 {
+            break;
+        }
+    }
+    unsafe { Port::<u8>::new(DATA_PORT).write(byte) };
+}
+
+/// Start showing `locks` on the LEDs, the interrupt handler sends the
+/// value when the keyboard acknowledges the command
+fn set_leds(locks: LockState) {
+    without_interrupts(|| {
+        PENDING_LEDS.store(locks.leds() as u16, Ordering::Relaxed);
+        write_data(SET_LEDS);
+    });
+}
+
+/// Make the LEDs show the initial lock state.
+///
+/// Must be called once the keyboard interrupt is delivered.
+pub fn init() {
+    set_leds(DECODER.lock().locks);
+}
+
+/// Return whether an LED change is waiting for the keyboard
+pub fn leds_pending() -> bool {
+    PENDING_LEDS.load(Ordering::Relaxed) != NO_LEDS
+}
+
+/// Return the state of the lock keys
+pub fn lock_state() -> LockState {
+    DECODER.lock().locks
+}
+
+/// Handle a byte read from the keyboard: answers to commands are consumed,
+/// scancodes are queued.
+///
+/// Called from the keyboard interrupt handler.
+pub fn handle_byte(byte: u8) {
+    match byte {
+        ACK => {
+            let leds = PENDING_LEDS.swap(NO_LEDS, Ordering::Relaxed);
+            if leds != NO_LEDS {
+                write_data(leds as u8);
+            }
+        }
+        // the LEDs are set again on the next lock key
+        RESEND => PENDING_LEDS.store(NO_LEDS, Ordering::Relaxed),
+        _ => push_scancode(byte),
+    }
+}
+
+/// Queue a scancode and wake the async reader
+pub fn push_scancode(scancode: u8) {
+    if QUEUE.push(scancode) {
+        WAKER.wake();
+    }
+}
+
+/// Return the number of scancodes dropped because nobody read them in time
+pub fn overflows() -> u64 {
+    QUEUE.overflows()
+}
+
+/// Return the number of scancodes waiting to be decoded
+pub fn pending() -> usize {
+    QUEUE.len()
+}
+
+/// Decode the queued scancodes up to the next key, without waiting
+pub fn try_read_key() -> Option<DecodedKey> {
+    let mut decoder = DECODER.lock();
+    while let Some(scancode) = QUEUE.pop() {
+        if let Ok(Some(event)) = decoder.keyboard.add_byte(scancode) {
+            if decoder.locks.update(&event) {
+                set_leds(decoder.locks);
+            }
+            if let Some(key) = decoder.keyboard.process_keyevent(event) {
+                return Some(key);
+            }
+        }
+    }
+    None
+}
+
+/// Halt until a key is pressed. Enables interrupts.
+pub fn read_key() -> DecodedKey {
+    loop {
+        // a key arriving between the check and `hlt` would not wake us,
+        // `enable_and_hlt` only lets interrupts in once halted
+        interrupts::disable();
+        if let Some(key) = try_read_key() {
+            interrupts::enable();
+            return key;
+        }
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Keys as they are typed, for async code
+pub struct KeyStream {
+    _private: (),
+}
+
+/// Return a stream of the typed keys, readers share the same keys
+pub fn keys() -> KeyStream {
+    KeyStream { _private: () }
+}
+
+// a waker left behind would be dropped by the interrupt handler
+impl Drop for KeyStream {
+    fn drop(&mut self) {
+        WAKER.take();
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = DecodedKey;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<DecodedKey>> {
+        if let Some(key) = try_read_key() {
+            return Poll::Ready(Some(key));
+        }
+        WAKER.register(cx.waker());
+        // a scancode may have been pushed before the waker was registered
+        match try_read_key() {
+            Some(key) => {
+                WAKER.take();
+                Poll::Ready(Some(key))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use core::cmp::min;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use lazy_static::lazy_static;
+use x86_64::PhysAddr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PhysFrame, Size4KiB,
+    mapper::UnmapError, frame::PhysFrameRange,
+};
+use crate::allocator::buddy_system::bitmap::bitmap_words_from;
+use crate::allocator::buddy_system::frame::LockedFrameAllocator;
+use crate::memory::memory_management::BootInfoFrameAllocator;
+
+pub const FRAME_SIZE: u64 = 4096;
+
+/// Physical memory the frame manager can hand out, frames above are unused
+pub const MAX_PHYSICAL_MEMORY: u64 = 4 << 30; // 4 GB
+
+const MAX_FRAMES: usize = (MAX_PHYSICAL_MEMORY / FRAME_SIZE) as usize;
+
+/// Free block bitmap of the frame allocator, outside the heap as the heap
+/// grows by allocating frames.
+static mut FRAME_BITMAP: [usize; bitmap_words_from(MAX_FRAMES, 0)] = [0; bitmap_words_from(MAX_FRAMES, 0)];
+
+lazy_static! {
+    /// Buddy allocator over the physical frame numbers of the whole machine.
+    static ref FRAMES: LockedFrameAllocator = LockedFrameAllocator::new(MAX_FRAMES, unsafe { &mut FRAME_BITMAP });
+}
+
+/// Kernel-wide physical frame manager.
+///
+/// A handle to the global buddy frame allocator, usable wherever the `x86_64`
+/// paging code expects a `FrameAllocator` or a `FrameDeallocator`.
+pub struct FrameManager;
+
+/// Seed the global frame allocator with the usable regions of the memory map.
+///
+/// The bootstrap allocator is consumed: the frames it already handed out
+/// (for the initial heap and its page tables) are counted as allocated, so
+/// they can be freed later, every other usable frame is given to the buddy
+/// allocator.
+///
+/// This function is unsafe because the caller must guarantee that the memory
+/// map is valid and that the bootstrap allocator is not used afterwards.
+pub unsafe fn init(memory_map: &'static MemoryMap, boot_allocator: BootInfoFrameAllocator) {
+    let mut skip = boot_allocator.allocated_frames();
+
+    without_interrupts(|| {
+        let mut frames = FRAMES.lock();
+        let usable_regions = memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        for region in usable_regions {
+            let mut start = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+
+            // frames already taken by the bootstrap allocator
+            let used = min(skip, end - start);
+            frames.add_allocated_frame(start, start + used);
+            start += used;
+            skip -= used;
+
+            if start < end {
+                frames.add_frame(start, end);
+            }
+        }
+    });
+}
+
+/// Allocate `count` physically contiguous frames.
+///
+/// The request is rounded up to a power of two and the returned range is
+/// aligned to that size.
+pub fn allocate_frames(count: usize) -> Option<PhysFrameRange> {
+    let first = without_interrupts(|| FRAMES.lock().alloc(count))?;
+    let start = frame_from_number(first);
+    Some(PhysFrame::range(start, start + count.next_power_of_two() as u64))
+}
+
+/// Allocate a frame, or return `None` right away if the frame allocator is
+/// locked, for code that may run while this CPU holds it.
+pub fn try_allocate_frame() -> Option<PhysFrame> {
+    let first = without_interrupts(|| FRAMES.try_lock()?.alloc(1))?;
+    Some(frame_from_number(first))
+}
+
+/// Give back a range returned by `allocate_frames`.
+///
+/// This function is unsafe because the caller must guarantee that the frames
+/// are no longer used. The range must be exactly the one that was allocated.
+pub unsafe fn deallocate_frames(range: PhysFrameRange) {
+    let first = frame_number(range.start);
+    let count = (range.end - range.start) as usize;
+    without_interrupts(|| FRAMES.lock().dealloc(first, count));
+}
+
+/// Unmap `page` and return its frame to the frame manager.
+///
+/// This function is unsafe because the caller must guarantee that nothing
+/// refers to the page or to the frame behind it anymore.
+pub unsafe fn unmap_and_free(
+    mapper: &mut impl Mapper<Size4KiB>,
+    page: Page<Size4KiB>,
+) -> Result<(), UnmapError> {
+    let (frame, flush) = mapper.unmap(page)?;
+    flush.flush();
+    FrameManager.deallocate_frame(frame);
+    Ok(())
+}
+
+/// Return the number of frames currently allocated
+pub fn stats_alloc_frames() -> usize {
+    without_interrupts(|| FRAMES.lock().stats_alloc_frames())
+}
+
+/// Return the total number of frames managed by the kernel
+pub fn stats_total_frames() -> usize {
+    without_interrupts(|| FRAMES.lock().stats_total_frames())
+}
+
+fn frame_number(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+fn frame_from_number(number: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(number as u64 * FRAME_SIZE))
+}
+
+unsafe impl FrameAllocator<Size4KiB> for FrameManager {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        allocate_frames(1).map(|range| range.start)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for FrameManager {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        deallocate_frames(PhysFrame::range(frame, frame + 1));
+    }
+}
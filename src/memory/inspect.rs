@@ -0,0 +1,141 @@
+use core::fmt;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use crate::memory::memory_management::phys_to_virt;
+use crate::{println, serial_println};
+
+/// Virtually and physically contiguous pages with the same access rights
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub virt: VirtAddr,
+    pub phys: PhysAddr,
+    pub size: u64,
+    /// Effective rights over all levels: writable and user accessible if
+    /// every level allows it, no execute if any level says so
+    pub flags: PageTableFlags,
+}
+
+impl Mapping {
+    pub fn end(&self) -> VirtAddr {
+        self.virt + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.virt <= addr && addr < self.end()
+    }
+
+    /// Return the physical address `addr` is mapped to
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        if self.contains(addr) {
+            Some(self.phys + (addr - self.virt))
+        } else {
+            None
+        }
+    }
+
+    /// Extend `self` with `next` if it continues it
+    fn merge(&mut self, next: &Mapping) -> bool {
+        if self.end() == next.virt && self.phys + self.size == next.phys && self.flags == next.flags {
+            self.size += next.size;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |flag, set, unset| if self.flags.contains(flag) { set } else { unset };
+        write!(f, "{:#018x}-{:#018x} -> {:#014x} {:>10} {} {} {} {}",
+               self.virt.as_u64(), self.end().as_u64(), self.phys.as_u64(), self.size,
+               flag(PageTableFlags::WRITABLE, "RW", "RO"),
+               flag(PageTableFlags::USER_ACCESSIBLE, "US", "SU"),
+               flag(PageTableFlags::NO_EXECUTE, "NX", "X "),
+               flag(PageTableFlags::GLOBAL, "G", "-"))
+    }
+}
+
+/// Call `f` with every mapping of the active page table, lowest address first.
+///
+/// Only present entries are followed, huge pages are reported as a whole.
+pub fn for_each_mapping(mut f: impl FnMut(&Mapping)) {
+    let mut pending: Option<Mapping> = None;
+    let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let level_4_table = table_at(Cr3::read().0.start_address());
+
+    walk(level_4_table, 4, 0, inherited, &mut |mapping| {
+        if let Some(current) = pending.as_mut() {
+            if current.merge(&mapping) {
+                return;
+            }
+            f(current);
+        }
+        pending = Some(mapping);
+    });
+    if let Some(current) = pending {
+        f(&current);
+    }
+}
+
+/// Return the coalesced mapping containing `addr`
+pub fn find(addr: VirtAddr) -> Option<Mapping> {
+    let mut found = None;
+    for_each_mapping(|mapping| {
+        if mapping.contains(addr) {
+            found = Some(*mapping);
+        }
+    });
+    found
+}
+
+/// Print the mappings over serial
+pub fn dump() {
+    for_each_mapping(|mapping| serial_println!("{}", mapping));
+}
+
+/// Print the mappings on the VGA console and over serial
+pub fn print() {
+    for_each_mapping(|mapping| {
+        println!("{}", mapping);
+        serial_println!("{}", mapping);
+    });
+}
+
+fn table_at(addr: PhysAddr) -> &'static PageTable {
+    unsafe { &*phys_to_virt(addr).as_ptr::<PageTable>() }
+}
+
+/// Visit the present entries of `table` at `level`, which maps from `base`
+fn walk(table: &PageTable, level: u32, base: u64, inherited: PageTableFlags, emit: &mut impl FnMut(Mapping)) {
+    let entry_size = 1u64 << (12 + 9 * (level - 1));
+
+    for (i, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        let mut virt = base + i as u64 * entry_size;
+        if level == 4 && i >= 256 {
+            // upper half, sign extend bit 47
+            virt |= 0xffff_0000_0000_0000;
+        }
+        let mut effective = inherited & flags & (PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE);
+        effective |= (inherited | flags) & PageTableFlags::NO_EXECUTE;
+
+        // bit 7 is PAT in a level 1 entry, not HUGE_PAGE
+        if level == 1 || (level <= 3 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+            emit(Mapping {
+                virt: VirtAddr::new(virt),
+                // bit 12 of a huge page entry is PAT, not part of the address
+                phys: entry.addr().align_down(entry_size),
+                size: entry_size,
+                flags: effective | (flags & PageTableFlags::GLOBAL),
+            });
+        } else {
+            walk(table_at(entry.addr()), level - 1, virt, effective, emit);
+        }
+    }
+}
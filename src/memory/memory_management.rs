@@ -1,19 +1,48 @@
 use x86_64::{structures::paging::PageTable, VirtAddr, PhysAddr};
-use x86_64::structures::paging::page_table::PageTableEntry;
 use x86_64::structures::paging::*;
 use x86_64::structures::paging::page_table::FrameError;
 use x86_64::registers::control::Cr3;
-use bootloader::BootInfo;
 use bootloader::bootinfo::MemoryRegionType;
-use crate::println;
 use crate::serial_println;
+use crate::sync::SpinLock;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
 
+/// Page table of the running kernel, available once `install_mapper` was called.
+static KERNEL_MAPPER: SpinLock<Option<OffsetPageTable<'static>>> = SpinLock::new(None);
+
+/// Virtual address where the bootloader maps the whole physical memory.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
 
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Make `mapper` the kernel-wide page table used by `with_mapper`.
+pub fn install_mapper(mapper: OffsetPageTable<'static>) {
+    without_interrupts(|| {
+        *KERNEL_MAPPER.lock() = Some(mapper);
+    });
+}
+
+/// Run `f` with the kernel page table, or return `None` if it is not installed yet.
+pub fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> Option<R> {
+    without_interrupts(|| KERNEL_MAPPER.lock().as_mut().map(f))
+}
+
+/// Like `with_mapper`, but return `None` right away if the page table is
+/// locked, for code that may run while this CPU holds it.
+pub fn try_with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> Option<R> {
+    without_interrupts(|| KERNEL_MAPPER.try_lock()?.as_mut().map(f))
+}
+
+/// Return the address through which the kernel reaches physical address `addr`.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable
 {
     use x86_64::registers::control::Cr3;
@@ -27,42 +56,6 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr
 }
 
-pub unsafe fn show_each_table_used_entries(boot_info: &'static BootInfo){
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let l4_table = unsafe { active_level_4_table(phys_mem_offset) };
-    for (i, entry) in l4_table.iter().enumerate() {
-        show_entry("L4", i, entry);
-        let l3_table = get_page_table(entry, boot_info);
-
-        for (i, entry) in l3_table.iter().enumerate() {
-            show_entry("L3", i, entry);
-            let l2_table = get_page_table(entry, boot_info);
-
-            for (i, entry) in l2_table.iter().enumerate() {
-                show_entry("L2", i, entry);
-                let l1_table = get_page_table(entry, boot_info);
-
-                for (i, entry) in l1_table.iter().enumerate() {
-                    show_entry("L1", i, entry);
-                }
-            }
-        }
-    }
-}
-
-unsafe fn get_page_table<'a>(entry: &'a PageTableEntry, boot_info: &'static BootInfo) -> &'a PageTable {
-    let phys = entry.frame().unwrap().start_address();
-    let virt = phys.as_u64() + boot_info.physical_memory_offset;
-    let ptr = VirtAddr::new(virt).as_mut_ptr();
-    unsafe { &*ptr }
-}
-
-unsafe fn show_entry(level: &str, i: usize, entry: &PageTableEntry ) {
-    if !entry.is_unused() {
-        println!("{} Entry {}: {:?}", level, i, entry);
-    }
-}
-
 pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr>
 {
     translate_addr_inner(addr, physical_memory_offset)
@@ -78,8 +71,11 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
     ];
     let mut frame = level_4_table_frame;
 
+    // size of the pages mapped by the entries of each level, none at level 4
+    let page_sizes = [None, Some(Size1GiB::SIZE), Some(Size2MiB::SIZE), Some(Size4KiB::SIZE)];
+
     // traverse the multi-level page table
-    for &index in &table_indexes {
+    for (&index, &page_size) in table_indexes.iter().zip(page_sizes.iter()) {
         // convert the frame into a page table reference
         let virt = physical_memory_offset + frame.start_address().as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
@@ -90,7 +86,12 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+            // a 1 GiB page at L3 or a 2 MiB page at L2 ends the walk, a huge
+            // page bit at L4 is invalid. Bit 12 of a huge page entry is PAT,
+            // not part of the address.
+            Err(FrameError::HugeFrame) => {
+                return page_size.map(|size| entry.addr().align_down(size) + (addr.as_u64() & (size - 1)))
+            }
         };
     }
 
@@ -124,6 +125,14 @@ impl BootInfoFrameAllocator {
         }
     }
 
+    /// Returns the number of frames handed out so far.
+    ///
+    /// The frames are taken in memory map order, so this is also the number
+    /// of usable frames (counted from the first usable region) that are in use.
+    pub fn allocated_frames(&self) -> usize {
+        self.next
+    }
+
     /// Returns an iterator over the usable frames specified in the memory map.
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         // get usable regions from memory map
@@ -139,13 +148,3 @@ impl BootInfoFrameAllocator {
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
 }
-
-const VIRTUAL_OFFSET: u64 = 0xC0000000;
-
-pub unsafe fn to_virt(phys_addr: &PhysAddr) -> Option<VirtAddr> {
-    if phys_addr.as_u64() < 0x100000000 {
-        Some(VirtAddr::new(phys_addr.as_u64() + VIRTUAL_OFFSET))
-    } else {
-        None
-    }
-}
\ No newline at end of file
@@ -1 +1,6 @@
 pub mod memory_management;
+pub mod frame_manager;
+pub mod vma;
+pub mod vmalloc;
+pub mod inspect;
+pub mod stack;
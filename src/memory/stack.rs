@@ -0,0 +1,119 @@
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::PageTableFlags;
+use crate::gdt::{self, DOUBLE_FAULT_IST_INDEX};
+use crate::memory::vma::{GUARD_SIZE, PAGE_SIZE};
+use crate::memory::vmalloc::{self, VmallocError};
+use crate::sync::SpinLock;
+use crate::thread::MAX_THREADS;
+
+/// Stacks not owned by a thread: the double fault and kernel main stacks,
+/// and a few for short-lived uses
+const SYSTEM_STACKS: usize = 6;
+
+/// Maximum number of kernel stacks alive at once, one per thread slot, as
+/// finished threads keep theirs until joined, plus the system stacks
+pub const MAX_STACKS: usize = MAX_THREADS + SYSTEM_STACKS;
+
+/// Pages of the stack `kernel_main` moves to
+pub const KERNEL_STACK_PAGES: usize = 64;
+
+/// Pages of the interrupt stacks
+pub const INTERRUPT_STACK_PAGES: usize = 5;
+
+/// A kernel stack with an unmapped guard page below it
+#[derive(Debug, Clone, Copy)]
+pub struct KernelStack {
+    pub name: &'static str,
+    /// Lowest usable address
+    pub bottom: VirtAddr,
+    /// Initial stack pointer, the stack grows down from here
+    pub top: VirtAddr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    /// `MAX_STACKS` stacks are allocated
+    Full,
+    /// The stack could not be mapped
+    Vmalloc(VmallocError),
+}
+
+impl KernelStack {
+    /// Return whether `addr` is in the guard page of the stack
+    pub fn guard_contains(&self, addr: VirtAddr) -> bool {
+        self.bottom - GUARD_SIZE <= addr && addr < self.bottom
+    }
+}
+
+static STACKS: SpinLock<[Option<KernelStack>; MAX_STACKS]> = SpinLock::new([None; MAX_STACKS]);
+
+/// Allocate a stack of `pages` pages.
+///
+/// The stack is placed by `vmalloc`, which leaves an unmapped guard page
+/// below it, so an overflow faults instead of overwriting other memory.
+pub fn allocate(pages: usize, name: &'static str) -> Result<KernelStack, StackError> {
+    let size = pages * PAGE_SIZE as usize;
+    let bottom = vmalloc::vmalloc(size, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE, name)
+        .map_err(StackError::Vmalloc)?;
+    let stack = KernelStack { name, bottom, top: bottom + size };
+
+    let registered = without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        let slot = stacks.iter_mut().find(|slot| slot.is_none())?;
+        *slot = Some(stack);
+        Some(())
+    });
+    if registered.is_none() {
+        unsafe { vmalloc::vfree(bottom) };
+        return Err(StackError::Full);
+    }
+    Ok(stack)
+}
+
+/// Free a stack returned by `allocate`.
+///
+/// This function is unsafe because the caller must guarantee that the stack
+/// is not in use.
+pub unsafe fn free(stack: KernelStack) {
+    without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        if let Some(slot) = stacks.iter_mut().find(|slot| slot.map_or(false, |s| s.bottom == stack.bottom)) {
+            *slot = None;
+        }
+    });
+    vmalloc::vfree(stack.bottom);
+}
+
+/// Return the stack whose guard page contains `addr`.
+///
+/// Called from the fault handlers, gives up if the table is locked.
+pub fn guard_hit(addr: VirtAddr) -> Option<KernelStack> {
+    let stacks = STACKS.try_lock()?;
+    stacks.iter().flatten().find(|stack| stack.guard_contains(addr)).copied()
+}
+
+/// Move the interrupt stacks to guarded stacks.
+///
+/// Must be called once `vmalloc` is available.
+pub fn init() {
+    let double_fault = allocate(INTERRUPT_STACK_PAGES, "double fault").expect("no memory for the double fault stack");
+    unsafe { gdt::set_interrupt_stack(DOUBLE_FAULT_IST_INDEX, double_fault.top) };
+}
+
+/// Continue execution on `stack` by calling `entry(arg)`.
+///
+/// This function is unsafe because nothing on the current stack may be used
+/// afterwards.
+pub unsafe fn switch_to(stack: &KernelStack, entry: extern "C" fn(u64) -> !, arg: u64) -> ! {
+    asm!(
+        "mov rsp, {top}",
+        "xor rbp, rbp",
+        "call {entry}",
+        "ud2",
+        top = in(reg) stack.top.as_u64(),
+        entry = in(reg) entry,
+        in("rdi") arg,
+        options(noreturn),
+    )
+}
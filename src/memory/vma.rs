@@ -0,0 +1,277 @@
+use core::ptr;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{
+    FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame,
+    Size2MiB, Size4KiB, mapper::UnmapError,
+};
+use crate::memory::frame_manager::{self, FrameManager};
+use crate::memory::memory_management::{phys_to_virt, try_with_mapper, with_mapper};
+use crate::memory::stack::MAX_STACKS;
+use crate::serial_println;
+use crate::sync::SpinLock;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Areas other than the kernel stacks: the heap, the vmalloc window and the
+/// regions mapped by `vmalloc` and `ioremap`
+const OTHER_VMAS: usize = 64;
+
+/// Maximum number of registered areas, each kernel stack is one
+pub const MAX_VMAS: usize = MAX_STACKS + OTHER_VMAS;
+
+/// Access allowed to an area, it is always readable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub const READ: Permissions = Permissions { write: false, execute: false };
+    pub const READ_WRITE: Permissions = Permissions { write: true, execute: false };
+    pub const READ_EXECUTE: Permissions = Permissions { write: false, execute: true };
+
+    /// Return the permissions granted by the page table `flags`
+    pub fn from_flags(flags: PageTableFlags) -> Self {
+        Permissions {
+            write: flags.contains(PageTableFlags::WRITABLE),
+            execute: !flags.contains(PageTableFlags::NO_EXECUTE),
+        }
+    }
+
+    /// Return the page table flags of a mapped page of the area
+    pub fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.write {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.execute {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// How the pages of an area get their frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// A zeroed frame is mapped on the first access to each page
+    Lazy,
+    /// Frames were allocated and mapped when the area was created
+    Allocated,
+    /// The owner maps the pages itself and owns their frames, a fault is a bug
+    Fixed,
+}
+
+/// A reserved range of kernel virtual memory
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub permissions: Permissions,
+    pub backing: Backing,
+    pub name: &'static str,
+}
+
+impl Vma {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    fn overlaps(&self, other: &Vma) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// Start or size is not page aligned, or the size is zero
+    Unaligned,
+    /// The range overlaps a registered area
+    Overlap,
+    /// `MAX_VMAS` areas are registered
+    Full,
+    /// No free range is large enough
+    NoSpace,
+}
+
+/// Unmapped pages left around the areas placed by `register_free`
+pub const GUARD_SIZE: u64 = PAGE_SIZE;
+
+/// Registered areas, kept outside the heap as they are searched on page faults
+static VMAS: SpinLock<[Option<Vma>; MAX_VMAS]> = SpinLock::new([None; MAX_VMAS]);
+
+/// Reserve the range described by `vma`
+pub fn register(vma: Vma) -> Result<(), VmaError> {
+    if vma.size == 0 || vma.size % PAGE_SIZE != 0 || !vma.start.is_aligned(PAGE_SIZE) {
+        return Err(VmaError::Unaligned);
+    }
+
+    without_interrupts(|| {
+        let mut vmas = VMAS.lock();
+        if vmas.iter().flatten().any(|other| other.overlaps(&vma)) {
+            return Err(VmaError::Overlap);
+        }
+        let slot = vmas.iter_mut().find(|slot| slot.is_none()).ok_or(VmaError::Full)?;
+        *slot = Some(vma);
+        Ok(())
+    })
+}
+
+/// Place `vma` at the lowest free address of [window_start, window_end)
+/// that is a multiple of `align`, and return its start, `vma.start` is ignored.
+///
+/// A guard gap of `GUARD_SIZE` is kept on both sides so that overruns fault.
+pub fn register_free(
+    window_start: VirtAddr,
+    window_end: VirtAddr,
+    align: u64,
+    mut vma: Vma,
+) -> Result<VirtAddr, VmaError> {
+    if vma.size == 0 || vma.size % PAGE_SIZE != 0 || align % PAGE_SIZE != 0 {
+        return Err(VmaError::Unaligned);
+    }
+
+    without_interrupts(|| {
+        let mut vmas = VMAS.lock();
+        let mut start = (window_start + GUARD_SIZE).align_up(align);
+        while let Some(other) = vmas.iter().flatten().find(|other| {
+            other.start < start + vma.size + GUARD_SIZE && start < other.end() + GUARD_SIZE
+        }) {
+            start = (other.end() + GUARD_SIZE).align_up(align);
+        }
+        if start + vma.size + GUARD_SIZE > window_end {
+            return Err(VmaError::NoSpace);
+        }
+
+        vma.start = start;
+        let slot = vmas.iter_mut().find(|slot| slot.is_none()).ok_or(VmaError::Full)?;
+        *slot = Some(vma);
+        Ok(start)
+    })
+}
+
+/// Remove the area starting at `start` and unmap its pages, 4 KiB or 2 MiB.
+///
+/// The frames of lazily backed and allocated areas are freed, those of
+/// fixed areas belong to their owner.
+///
+/// This function is unsafe because the caller must guarantee that nothing
+/// refers to the memory of the area anymore.
+pub unsafe fn unregister(start: VirtAddr) -> Option<Vma> {
+    let vma = without_interrupts(|| {
+        let mut vmas = VMAS.lock();
+        let slot = vmas.iter_mut().find(|slot| slot.map_or(false, |vma| vma.start == start))?;
+        slot.take()
+    })?;
+
+    let owned = vma.backing != Backing::Fixed;
+    with_mapper(|mapper| {
+        let mut addr = vma.start;
+        while addr < vma.end() {
+            match mapper.unmap(Page::<Size4KiB>::containing_address(addr)) {
+                Ok((frame, flush)) => {
+                    flush.flush();
+                    if owned {
+                        FrameManager.deallocate_frame(frame);
+                    }
+                    addr += PAGE_SIZE;
+                }
+                Err(UnmapError::ParentEntryHugePage) => {
+                    if let Ok((frame, flush)) = mapper.unmap(Page::<Size2MiB>::containing_address(addr)) {
+                        flush.flush();
+                        if owned {
+                            let first = PhysFrame::containing_address(frame.start_address());
+                            frame_manager::deallocate_frames(PhysFrame::range(first, first + 512));
+                        }
+                    }
+                    addr += Size2MiB::SIZE;
+                }
+                // lazy pages never touched are not mapped
+                Err(_) => addr += PAGE_SIZE,
+            }
+        }
+    });
+    Some(vma)
+}
+
+/// Return the area containing `addr`
+pub fn find(addr: VirtAddr) -> Option<Vma> {
+    without_interrupts(|| VMAS.lock().iter().flatten().find(|vma| vma.contains(addr)).copied())
+}
+
+/// Like `find`, but return `None` right away if the area table is locked
+fn try_find(addr: VirtAddr) -> Option<Vma> {
+    without_interrupts(|| VMAS.try_lock()?.iter().flatten().find(|vma| vma.contains(addr)).copied())
+}
+
+/// Return a copy of the registered areas
+pub fn areas() -> [Option<Vma>; MAX_VMAS] {
+    without_interrupts(|| *VMAS.lock())
+}
+
+/// Print the registered areas over serial, lowest first
+pub fn dump() {
+    let mut areas = areas();
+    areas.sort_unstable_by_key(|vma| vma.map_or(u64::MAX, |vma| vma.start.as_u64()));
+    for vma in areas.iter().flatten() {
+        serial_println!("{:#018x}-{:#018x} r{}{} {:?} {}",
+                        vma.start.as_u64(), vma.end().as_u64(),
+                        if vma.permissions.write { "w" } else { "-" },
+                        if vma.permissions.execute { "x" } else { "-" },
+                        vma.backing, vma.name);
+    }
+}
+
+/// Resolve a page fault at `addr`.
+///
+/// Returns `true` if a frame was mapped and the faulting access can be
+/// retried, `false` if the fault is an error.
+///
+/// The fault may come from code holding the area table, the frame allocator
+/// or the page table, so none of them is waited for: the fault is reported
+/// as an error if one is locked.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let vma = match try_find(addr) {
+        Some(vma) if vma.backing == Backing::Lazy => vma,
+        _ => return false,
+    };
+
+    // the page is there, the access is not allowed
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        || error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !vma.permissions.write
+        || error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) && !vma.permissions.execute
+    {
+        return false;
+    }
+
+    let frame = match frame_manager::try_allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    unsafe {
+        ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize);
+    }
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let mapped = try_with_mapper(|mapper| {
+        match unsafe { mapper.map_to(page, frame, vma.permissions.page_flags(), &mut FrameManager) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => false,
+        }
+    });
+    if mapped != Some(true) {
+        unsafe { FrameManager.deallocate_frame(frame) };
+        return false;
+    }
+    true
+}
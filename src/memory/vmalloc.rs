@@ -0,0 +1,227 @@
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame,
+    Size2MiB, Size4KiB, mapper::MapToError,
+};
+use crate::allocator::alloc::{HEAP_MAX_SIZE, HEAP_START};
+use crate::memory::frame_manager::{self, FrameManager};
+use crate::memory::memory_management::with_mapper;
+use crate::memory::vma::{self, Backing, Permissions, Vma, VmaError, PAGE_SIZE};
+
+/// Start of the window kernel regions are placed in
+pub const VMALLOC_START: u64 = 0x_6000_0000_0000;
+/// Size of the window, 1 TiB or two level 4 entries
+pub const VMALLOC_SIZE: u64 = 1 << 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmallocError {
+    /// No room for the region in the window or in the area table
+    Reserve(VmaError),
+    /// Not enough physical memory for the region or its page tables
+    OutOfFrames,
+    /// A page of the region was mapped outside of the area table
+    AlreadyMapped,
+}
+
+impl From<VmaError> for VmallocError {
+    fn from(error: VmaError) -> Self {
+        VmallocError::Reserve(error)
+    }
+}
+
+/// Register the regions whose address is fixed at build time.
+///
+/// Must be called once the kernel page table is installed.
+pub fn init() {
+    with_mapper(|mapper| {
+        let level_4_table = mapper.level_4_table();
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(VMALLOC_START)).p4_index();
+        let last = Page::<Size4KiB>::containing_address(VirtAddr::new(VMALLOC_START + VMALLOC_SIZE - 1)).p4_index();
+        for index in u16::from(first)..=u16::from(last) {
+            assert!(level_4_table[index as usize].is_unused(), "vmalloc window already mapped");
+        }
+    }).expect("kernel page table not installed");
+
+    vma::register(Vma {
+        start: VirtAddr::new(HEAP_START as u64),
+        size: HEAP_MAX_SIZE as u64,
+        permissions: Permissions::READ_WRITE,
+        backing: Backing::Fixed,
+        name: "kernel heap",
+    }).expect("kernel heap overlaps a region");
+}
+
+fn window() -> (VirtAddr, VirtAddr) {
+    (VirtAddr::new(VMALLOC_START), VirtAddr::new(VMALLOC_START + VMALLOC_SIZE))
+}
+
+fn page_align(size: usize) -> u64 {
+    (size as u64 + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+}
+
+fn huge_page_align(size: usize) -> u64 {
+    (size as u64 + Size2MiB::SIZE - 1) / Size2MiB::SIZE * Size2MiB::SIZE
+}
+
+/// Disable caching for device memory unless write-through was asked for
+fn uncached(flags: PageTableFlags) -> PageTableFlags {
+    if flags.contains(PageTableFlags::WRITE_THROUGH) {
+        flags
+    } else {
+        flags | PageTableFlags::NO_CACHE
+    }
+}
+
+/// Reserve `size` bytes of address space the caller maps itself, like
+/// stacks or temporary mappings
+pub fn reserve(size: usize, permissions: Permissions, name: &'static str) -> Result<VirtAddr, VmallocError> {
+    let (start, end) = window();
+    let vma = Vma { start, size: page_align(size), permissions, backing: Backing::Fixed, name };
+    Ok(vma::register_free(start, end, PAGE_SIZE, vma)?)
+}
+
+/// Reserve `size` bytes backed by zeroed frames on first access
+pub fn vmalloc_lazy(size: usize, permissions: Permissions, name: &'static str) -> Result<VirtAddr, VmallocError> {
+    let (start, end) = window();
+    let vma = Vma { start, size: page_align(size), permissions, backing: Backing::Lazy, name };
+    Ok(vma::register_free(start, end, PAGE_SIZE, vma)?)
+}
+
+/// Allocate and map `size` bytes of virtually contiguous memory
+pub fn vmalloc(size: usize, flags: PageTableFlags, name: &'static str) -> Result<VirtAddr, VmallocError> {
+    let (start, end) = window();
+    let vma = Vma {
+        start,
+        size: page_align(size),
+        permissions: Permissions::from_flags(flags),
+        backing: Backing::Allocated,
+        name,
+    };
+    let start = vma::register_free(start, end, PAGE_SIZE, vma)?;
+
+    let result = map_pages(start, vma.size, flags, true, |_| FrameManager.allocate_frame());
+    if result.is_err() {
+        unsafe { vma::unregister(start) };
+    }
+    result.map(|_| start)
+}
+
+/// Map the physical range [phys, phys + size), like device registers,
+/// and return the address of `phys`.
+///
+/// Caching is disabled unless `flags` says otherwise. The frames are not
+/// given to the frame manager when the region is freed.
+pub fn ioremap(phys: PhysAddr, size: usize, flags: PageTableFlags, name: &'static str) -> Result<VirtAddr, VmallocError> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let offset = phys - first.start_address();
+    let (start, end) = window();
+    let vma = Vma {
+        start,
+        size: page_align(offset as usize + size),
+        permissions: Permissions::from_flags(flags),
+        backing: Backing::Fixed,
+        name,
+    };
+    let start = vma::register_free(start, end, PAGE_SIZE, vma)?;
+
+    let result = map_pages(start, vma.size, uncached(flags), false, |i| Some(first + i));
+    if result.is_err() {
+        unsafe { vma::unregister(start) };
+    }
+    result.map(|_| start + offset)
+}
+
+/// Allocate and map `size` bytes with 2 MiB pages, each backed by
+/// physically contiguous memory
+pub fn vmalloc_huge(size: usize, flags: PageTableFlags, name: &'static str) -> Result<VirtAddr, VmallocError> {
+    let (start, end) = window();
+    let vma = Vma {
+        start,
+        size: huge_page_align(size),
+        permissions: Permissions::from_flags(flags),
+        backing: Backing::Allocated,
+        name,
+    };
+    let start = vma::register_free(start, end, Size2MiB::SIZE, vma)?;
+
+    let result = map_pages(start, vma.size, flags, true, |_| {
+        let frames = frame_manager::allocate_frames((Size2MiB::SIZE / PAGE_SIZE) as usize)?;
+        Some(PhysFrame::<Size2MiB>::containing_address(frames.start.start_address()))
+    });
+    if result.is_err() {
+        unsafe { vma::unregister(start) };
+    }
+    result.map(|_| start)
+}
+
+/// Map the physical range [phys, phys + size) with 2 MiB pages, like a
+/// framebuffer, and return the address of `phys`, which must be 2 MiB aligned.
+///
+/// Caching is disabled unless `flags` says otherwise.
+pub fn ioremap_huge(phys: PhysAddr, size: usize, flags: PageTableFlags, name: &'static str) -> Result<VirtAddr, VmallocError> {
+    let first = PhysFrame::<Size2MiB>::from_start_address(phys)
+        .map_err(|_| VmallocError::Reserve(VmaError::Unaligned))?;
+    let (start, end) = window();
+    let vma = Vma {
+        start,
+        size: huge_page_align(size),
+        permissions: Permissions::from_flags(flags),
+        backing: Backing::Fixed,
+        name,
+    };
+    let start = vma::register_free(start, end, Size2MiB::SIZE, vma)?;
+
+    let result = map_pages(start, vma.size, uncached(flags), false, |i| Some(first + i));
+    if result.is_err() {
+        unsafe { vma::unregister(start) };
+    }
+    result.map(|_| start)
+}
+
+/// Unmap and release the region containing `addr`.
+///
+/// This function is unsafe because the caller must guarantee that nothing
+/// refers to the region anymore.
+pub unsafe fn vfree(addr: VirtAddr) -> Option<Vma> {
+    let vma = vma::find(addr)?;
+    vma::unregister(vma.start)
+}
+
+/// Map the `size` bytes at `start` with pages of size `S` to the frames
+/// returned by `frame(page index)`, the frames are given back on failure
+/// if they are `owned`
+fn map_pages<S: PageSize>(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    owned: bool,
+    mut frame: impl FnMut(u64) -> Option<PhysFrame<S>>,
+) -> Result<(), VmallocError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let mut flags = flags | PageTableFlags::PRESENT;
+    if S::SIZE != Size4KiB::SIZE {
+        flags |= PageTableFlags::HUGE_PAGE;
+    }
+    with_mapper(|mapper| {
+        let first = Page::<S>::containing_address(start);
+        for i in 0..size / S::SIZE {
+            let frame = frame(i).ok_or(VmallocError::OutOfFrames)?;
+            match unsafe { mapper.map_to(first + i, frame, flags, &mut FrameManager) } {
+                Ok(flush) => flush.flush(),
+                Err(error) => {
+                    if owned {
+                        let first = PhysFrame::containing_address(frame.start_address());
+                        unsafe { frame_manager::deallocate_frames(PhysFrame::range(first, first + S::SIZE / PAGE_SIZE)) };
+                    }
+                    return Err(match error {
+                        MapToError::FrameAllocationFailed => VmallocError::OutOfFrames,
+                        _ => VmallocError::AlreadyMapped,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }).unwrap_or(Err(VmallocError::OutOfFrames))
+}
@@ -1,22 +1,103 @@
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
 use uart_16550::SerialPort;
-use spin::Mutex;
 use lazy_static::lazy_static;
 use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use crate::interrupts;
+use crate::sync::IrqMutex;
+use crate::task::queue::ByteQueue;
+
+const COM1: u16 = 0x3f8;
+const LINE_STATUS: u16 = COM1 + 5;
+const DATA_READY: u8 = 1;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    pub static ref SERIAL1: IrqMutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
-        Mutex::new(serial_port)
+        // `init` enabled the receive interrupt, `enable_apic` routes it on the APIC
+        interrupts::unmask_pic_irq(interrupts::COM1_IRQ);
+        IrqMutex::new(serial_port)
     };
 }
 
+/// Bytes received by the interrupt handler
+static RECEIVED: ByteQueue = ByteQueue::new();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Queue the bytes the UART received, `init` enabled its receive interrupt.
+///
+/// Called from the COM1 interrupt handler, reads the registers directly as
+/// `SERIAL1` may be locked by the interrupted code.
+pub fn handle_interrupt() {
+    unsafe {
+        while Port::<u8>::new(LINE_STATUS).read() & DATA_READY != 0 {
+            push_received(Port::<u8>::new(COM1).read());
+        }
+    }
+}
+
+/// Queue a received byte and wake the async reader
+pub fn push_received(byte: u8) {
+    if RECEIVED.push(byte) {
+        WAKER.wake();
+    }
+}
+
+/// Return the next received byte, without waiting
+pub fn try_read_byte() -> Option<u8> {
+    // one reader at a time
+    without_interrupts(|| RECEIVED.pop())
+}
+
+/// Return the number of received bytes dropped because nobody read them
+pub fn overflows() -> u64 {
+    RECEIVED.overflows()
+}
+
+/// Bytes as they are received, for async code
+pub struct ByteStream {
+    _private: (),
+}
+
+/// Return a stream of the received bytes, readers share the same bytes
+pub fn bytes() -> ByteStream {
+    ByteStream { _private: () }
+}
+
+// a waker left behind would be dropped by the interrupt handler
+impl Drop for ByteStream {
+    fn drop(&mut self) {
+        WAKER.take();
+    }
+}
+
+impl Stream for ByteStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        if let Some(byte) = try_read_byte() {
+            return Poll::Ready(Some(byte));
+        }
+        WAKER.register(cx.waker());
+        // a byte may have been received before the waker was registered
+        match try_read_byte() {
+            Some(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    without_interrupts(|| {
-        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
-    });
+    SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
 }
 
 /// Prints to the host through the serial interface.
@@ -0,0 +1,77 @@
+use alloc::string::String;
+use futures_util::stream::StreamExt;
+use pc_keyboard::DecodedKey;
+use crate::keyboard::{self, Layout};
+use crate::thread;
+use crate::{print, println};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    Unknown,
+    /// Wrong arguments, with the usage of the command
+    Usage(&'static str),
+}
+
+const BACKSPACE: char = '\u{8}';
+
+/// Run the command `line`
+pub fn run(line: &str) -> Result<(), CommandError> {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (None, _, _) => Ok(()),
+        (Some("help"), None, _) => {
+            println!("help             this list");
+            println!("layout           show the keyboard layout");
+            println!("layout <name>    switch to the us, uk, dvorak or azerty layout");
+            println!("threads          list the threads with their state and CPU time");
+            Ok(())
+        }
+        (Some("layout"), None, _) => {
+            println!("{}", keyboard::layout().name());
+            Ok(())
+        }
+        (Some("layout"), Some(name), None) => {
+            let layout = Layout::from_name(name).ok_or(CommandError::Usage("layout us|uk|dvorak|azerty"))?;
+            keyboard::set_layout(layout);
+            Ok(())
+        }
+        (Some("layout"), _, _) => Err(CommandError::Usage("layout us|uk|dvorak|azerty")),
+        (Some("threads"), None, _) => {
+            thread::dump();
+            Ok(())
+        }
+        (Some("threads"), _, _) => Err(CommandError::Usage("threads")),
+        _ => Err(CommandError::Unknown),
+    }
+}
+
+/// Read and run commands forever
+pub async fn run_forever() {
+    let mut keys = keyboard::keys();
+    let mut line = String::new();
+    print!("> ");
+    while let Some(key) = keys.next().await {
+        match key {
+            DecodedKey::Unicode('\n') => {
+                println!();
+                match run(&line) {
+                    Ok(()) => {}
+                    Err(CommandError::Unknown) => println!("unknown command, try help"),
+                    Err(CommandError::Usage(usage)) => println!("usage: {}", usage),
+                }
+                line.clear();
+                print!("> ");
+            }
+            DecodedKey::Unicode(BACKSPACE) => {
+                if line.pop().is_some() {
+                    print!("{}", BACKSPACE);
+                }
+            }
+            DecodedKey::Unicode(character) if !character.is_control() => {
+                line.push(character);
+                print!("{}", character);
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use super::{MutexGuard, WaitQueue};
+
+/// Blocks threads until another one notifies them that the state behind a
+/// `Mutex` changed
+pub struct Condvar {
+    /// Bumped by every notify, a waiter returns once it changed
+    generation: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar { generation: AtomicU64::new(0), waiters: WaitQueue::new() }
+    }
+
+    /// Release the mutex of `guard`, block until notified and lock it again.
+    ///
+    /// A notify after the mutex is released is not missed, but the state
+    /// may change again before the mutex is locked, so it must be checked in
+    /// a loop, as `wait_while` does.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let generation = self.generation.load(Ordering::Acquire);
+        drop(guard);
+        self.waiters.wait_until(|| self.generation.load(Ordering::Acquire) != generation);
+        mutex.lock()
+    }
+
+    /// Wait while `condition` returns `true` for the data of `guard`
+    pub fn wait_while<'a, T: ?Sized>(&self, mut guard: MutexGuard<'a, T>,
+                                     mut condition: impl FnMut(&mut T) -> bool) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wake the thread waiting the longest, return `false` if there is none
+    pub fn notify_one(&self) -> bool {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.notify_one()
+    }
+
+    /// Wake every waiting thread and return their number
+    pub fn notify_all(&self) -> usize {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.notify_all()
+    }
+}
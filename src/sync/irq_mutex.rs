@@ -0,0 +1,80 @@
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use x86_64::instructions::interrupts;
+use super::lockdep;
+
+/// A spinlock that disables interrupts while held.
+///
+/// An interrupt handler cannot run on top of the holder, so a handler may
+/// take the lock too without deadlocking, and the holder is not preempted.
+pub struct IrqMutex<T: ?Sized> {
+    inner: spin::Mutex<T>,
+}
+
+pub struct IrqMutexGuard<'a, T: ?Sized> {
+    lock: usize,
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    /// Whether interrupts were enabled before the lock was taken
+    enabled: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqMutex { inner: spin::Mutex::new(value) }
+    }
+}
+
+impl<T: ?Sized> IrqMutex<T> {
+    fn id(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        lockdep::acquire(self.id(), false);
+        IrqMutexGuard { lock: self.id(), guard: ManuallyDrop::new(self.inner.lock()), enabled }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<T>> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                lockdep::acquired(self.id());
+                Some(IrqMutexGuard { lock: self.id(), guard: ManuallyDrop::new(guard), enabled })
+            }
+            None => {
+                if enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for IrqMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for IrqMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: ?Sized> Drop for IrqMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // unlocked before interrupts come back
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        lockdep::release(self.lock);
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
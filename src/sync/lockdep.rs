@@ -0,0 +1,219 @@
+//! Lock order checking, in debug builds.
+//!
+//! Each lock is known by its address. Taking a lock while holding others
+//! records that they come first; taking them in the opposite order later,
+//! on any thread, is a possible deadlock and panics, as does taking a lock
+//! the thread already holds. Interrupt handlers hold their locks apart
+//! from the thread they interrupted. Locks taken before the scheduler
+//! started are not tracked.
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::interrupts;
+use crate::thread::{self, ThreadId, MAX_THREADS};
+
+/// Locks a thread can hold at once and still be checked
+pub const MAX_HELD: usize = 8;
+
+/// Orders remembered, later ones are not checked
+pub const MAX_EDGES: usize = 128;
+
+/// Who takes a lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Holder {
+    Thread(ThreadId),
+    /// The interrupt handlers, whatever thread they interrupted
+    Interrupt,
+}
+
+#[derive(Clone, Copy)]
+struct Held {
+    holder: Option<Holder>,
+    locks: [usize; MAX_HELD],
+    len: usize,
+}
+
+/// Lock orders seen and locks held by each thread and by interrupt handlers
+pub struct Graph {
+    /// `(first, then)`: `then` was taken while holding `first`
+    edges: [(usize, usize); MAX_EDGES],
+    len: usize,
+    held: [Held; MAX_THREADS + 1],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// Taken again by its holder
+    Recursive,
+    /// Taken while holding the lock, which was taken after it before
+    Inversion(usize),
+}
+
+/// Only locked with interrupts disabled, as interrupt handlers take locks
+static GRAPH: Mutex<Graph> = Mutex::new(Graph::new());
+
+impl Graph {
+    pub const fn new() -> Self {
+        Graph {
+            edges: [(0, 0); MAX_EDGES],
+            len: 0,
+            held: [Held { holder: None, locks: [0; MAX_HELD], len: 0 }; MAX_THREADS + 1],
+        }
+    }
+
+    fn held(&mut self, holder: Holder) -> Option<&mut Held> {
+        let slot = self.held.iter().position(|held| held.holder == Some(holder))
+            .or_else(|| self.held.iter().position(|held| held.holder.is_none()))?;
+        let held = &mut self.held[slot];
+        held.holder = Some(holder);
+        Some(held)
+    }
+
+    /// Return whether `to` is taken after `from`, directly or through other
+    /// locks
+    fn reaches(&self, from: usize, to: usize) -> bool {
+        let mut pending = [0; MAX_EDGES];
+        let mut visited = [0; MAX_EDGES];
+        let (mut pending_len, mut visited_len) = (1, 0);
+        pending[0] = from;
+        while pending_len > 0 {
+            pending_len -= 1;
+            let lock = pending[pending_len];
+            if lock == to {
+                return true;
+            }
+            if visited[..visited_len].contains(&lock) {
+                continue;
+            }
+            visited[visited_len] = lock;
+            visited_len += 1;
+            for &(first, then) in self.edges[..self.len].iter() {
+                if first == lock && pending_len < MAX_EDGES {
+                    pending[pending_len] = then;
+                    pending_len += 1;
+                }
+            }
+        }
+        false
+    }
+
+    fn add_edge(&mut self, first: usize, then: usize) {
+        if self.len < MAX_EDGES && !self.edges[..self.len].contains(&(first, then)) {
+            self.edges[self.len] = (first, then);
+            self.len += 1;
+        }
+    }
+
+    /// Return why `holder` may not take `lock`, and record the orders it
+    /// adds if it may
+    pub fn check(&mut self, holder: Holder, lock: usize, recursive: bool) -> Option<Violation> {
+        let held = *self.held(holder)?;
+        let held = &held.locks[..held.len];
+        if held.contains(&lock) {
+            return if recursive { None } else { Some(Violation::Recursive) };
+        }
+        for &first in held {
+            if self.reaches(lock, first) {
+                return Some(Violation::Inversion(first));
+            }
+            self.add_edge(first, lock);
+        }
+        None
+    }
+
+    /// Record `lock` as held by `holder`
+    pub fn push(&mut self, holder: Holder, lock: usize) {
+        if let Some(held) = self.held(holder) {
+            if held.len < MAX_HELD {
+                held.locks[held.len] = lock;
+                held.len += 1;
+            }
+        }
+    }
+
+    /// Record that `holder` released `lock`
+    pub fn pop(&mut self, holder: Holder, lock: usize) {
+        let slot = match self.held.iter().position(|held| held.holder == Some(holder)) {
+            Some(slot) => slot,
+            None => return,
+        };
+        let held = &mut self.held[slot];
+        if let Some(index) = held.locks[..held.len].iter().rposition(|&held| held == lock) {
+            held.locks.copy_within(index + 1..held.len, index);
+            held.len -= 1;
+        }
+        if held.len == 0 {
+            held.holder = None;
+        }
+    }
+}
+
+fn tracked() -> Option<Holder> {
+    if !cfg!(debug_assertions) || !thread::is_initialized() {
+        None
+    } else if interrupts::in_interrupt() {
+        Some(Holder::Interrupt)
+    } else {
+        Some(Holder::Thread(thread::current()))
+    }
+}
+
+/// Check that `lock` may be taken by the current thread or interrupt
+/// handler, before waiting for it, and record it as held.
+///
+/// `recursive` lets a thread take a lock it holds again, for readers.
+pub fn acquire(lock: usize, recursive: bool) {
+    let holder = match tracked() {
+        Some(holder) => holder,
+        None => return,
+    };
+    let violation = without_interrupts(|| {
+        let mut graph = GRAPH.lock();
+        let violation = graph.check(holder, lock, recursive);
+        graph.push(holder, lock);
+        violation
+    });
+    match violation {
+        None => {}
+        Some(Violation::Recursive) => panic!("lock {:#x} taken again by {:?}", lock, holder),
+        Some(Violation::Inversion(first)) => panic!(
+            "lock order inversion: {:#x} taken while holding {:#x} by {:?}, the reverse order was seen before",
+            lock, first, holder),
+    }
+}
+
+/// Record `lock` as held after a successful `try_lock`, which cannot
+/// deadlock and is not checked
+pub fn acquired(lock: usize) {
+    if let Some(holder) = tracked() {
+        without_interrupts(|| GRAPH.lock().push(holder, lock));
+    }
+}
+
+/// Record that the current thread or interrupt handler released `lock`
+pub fn release(lock: usize) {
+    let holder = match tracked() {
+        Some(holder) => holder,
+        None => return,
+    };
+    without_interrupts(|| GRAPH.lock().pop(holder, lock));
+}
+
+/// Forget the orders of `lock`, whose address may be reused by another one
+pub fn forget(lock: usize) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    without_interrupts(|| {
+        let mut graph = GRAPH.lock();
+        let mut kept = 0;
+        for index in 0..graph.len {
+            let (first, then) = graph.edges[index];
+            if first != lock && then != lock {
+                graph.edges[kept] = (first, then);
+                kept += 1;
+            }
+        }
+        graph.len = kept;
+    });
+}
@@ -0,0 +1,21 @@
+//! Locks for threads: `IrqMutex` spins with interrupts disabled and may be
+//! shared with interrupt handlers, `SpinLock` spins with preemption
+//! disabled, the others put the waiting thread to sleep in a `WaitQueue`
+//! until the holder wakes it.
+
+pub mod lockdep;
+pub mod irq_mutex;
+pub mod spin_lock;
+pub mod wait_queue;
+pub mod mutex;
+pub mod rwlock;
+pub mod semaphore;
+pub mod condvar;
+
+pub use self::condvar::Condvar;
+pub use self::irq_mutex::{IrqMutex, IrqMutexGuard};
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::semaphore::Semaphore;
+pub use self::spin_lock::{SpinLock, SpinLockGuard};
+pub use self::wait_queue::WaitQueue;
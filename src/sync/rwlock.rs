@@ -0,0 +1,153 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::{lockdep, WaitQueue};
+
+/// Set in `RwLock::state` while a writer holds the lock
+const WRITER: usize = !(usize::MAX >> 1);
+
+/// A lock held by any number of readers or by one writer, whose waiters
+/// sleep until it is released.
+///
+/// Readers are let in while others read, so a steady stream of readers
+/// keeps a writer waiting.
+pub struct RwLock<T: ?Sized> {
+    /// `WRITER`, or the number of readers
+    state: AtomicUsize,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    fn id(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    fn take_read(&self) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & WRITER != 0 {
+                return false;
+            }
+            match self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(current) => state = current,
+            }
+        }
+    }
+
+    fn take_write(&self) -> bool {
+        self.state.compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    /// Block until no writer holds the lock and take it for reading
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        lockdep::acquire(self.id(), true);
+        if !self.take_read() {
+            self.waiters.wait_until(|| self.take_read());
+        }
+        RwLockReadGuard { lock: self }
+    }
+
+    /// Block until nobody holds the lock and take it for writing
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        lockdep::acquire(self.id(), false);
+        if !self.take_write() {
+            self.waiters.wait_until(|| self.take_write());
+        }
+        RwLockWriteGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        if !self.take_read() {
+            return None;
+        }
+        lockdep::acquired(self.id());
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        if !self.take_write() {
+            return None;
+        }
+        lockdep::acquired(self.id());
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    /// Return the number of readers holding the lock
+    pub fn readers(&self) -> usize {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & WRITER != 0 { 0 } else { state }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLock<T> {
+    fn drop(&mut self) {
+        lockdep::forget(self.id());
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.lock.id());
+        // only a writer can be waiting, for the last reader
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.lock.waiters.notify_all();
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.lock.id());
+        self.lock.state.store(0, Ordering::Release);
+        self.lock.waiters.notify_all();
+    }
+}
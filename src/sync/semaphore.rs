@@ -0,0 +1,47 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::WaitQueue;
+
+/// A count of permits, acquiring one sleeps until one is available.
+///
+/// `release` does not allocate or block, so interrupt handlers may use it
+/// to wake a thread.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore { permits: AtomicUsize::new(permits), waiters: WaitQueue::new() }
+    }
+
+    /// Take a permit if one is available
+    pub fn try_acquire(&self) -> bool {
+        let mut permits = self.permits.load(Ordering::Relaxed);
+        while permits > 0 {
+            match self.permits.compare_exchange_weak(permits, permits - 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(current) => permits = current,
+            }
+        }
+        false
+    }
+
+    /// Block until a permit is available and take it
+    pub fn acquire(&self) {
+        if !self.try_acquire() {
+            self.waiters.wait_until(|| self.try_acquire());
+        }
+    }
+
+    /// Add a permit and wake a waiter to take it
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.notify_one();
+    }
+
+    /// Return the number of permits available
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
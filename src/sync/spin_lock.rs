@@ -0,0 +1,74 @@
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use crate::thread;
+use super::lockdep;
+
+/// A spinlock that disables preemption while held.
+///
+/// The timer does not switch away from the holder, so nobody spins on the
+/// lock while its holder waits for the CPU. Interrupts stay enabled, an
+/// interrupt handler may only `try_lock` it, use `IrqMutex` for locks the
+/// handlers must take.
+pub struct SpinLock<T: ?Sized> {
+    inner: spin::Mutex<T>,
+}
+
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: usize,
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinLock { inner: spin::Mutex::new(value) }
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    fn id(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        thread::preempt_disable();
+        lockdep::acquire(self.id(), false);
+        SpinLockGuard { lock: self.id(), guard: ManuallyDrop::new(self.inner.lock()) }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        thread::preempt_disable();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                lockdep::acquired(self.id());
+                Some(SpinLockGuard { lock: self.id(), guard: ManuallyDrop::new(guard) })
+            }
+            None => {
+                thread::preempt_enable();
+                None
+            }
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: ?Sized> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        // unlocked before the holder may be switched away from
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        lockdep::release(self.lock);
+        thread::preempt_enable();
+    }
+}
@@ -0,0 +1,91 @@
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::thread::{self, ThreadId, MAX_THREADS};
+use super::IrqMutex;
+
+/// Threads blocked until a condition holds, woken in FIFO order.
+///
+/// The queue has a fixed size, a thread waits in one queue at a time, so
+/// waking does not allocate and may be done from interrupt handlers.
+pub struct WaitQueue {
+    waiters: IrqMutex<Waiters>,
+}
+
+struct Waiters {
+    ids: [Option<ThreadId>; MAX_THREADS],
+    head: usize,
+    len: usize,
+}
+
+impl Waiters {
+    fn push(&mut self, id: ThreadId) {
+        assert!(self.len < MAX_THREADS, "wait queue overflow");
+        self.ids[(self.head + self.len) % MAX_THREADS] = Some(id);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<ThreadId> {
+        if self.len == 0 {
+            return None;
+        }
+        let id = self.ids[self.head].take();
+        self.head = (self.head + 1) % MAX_THREADS;
+        self.len -= 1;
+        id
+    }
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: IrqMutex::new(Waiters { ids: [None; MAX_THREADS], head: 0, len: 0 }),
+        }
+    }
+
+    /// Block the current thread until `condition` returns `true`.
+    ///
+    /// `condition` runs with the queue locked, so a notify after the state
+    /// it checks changed cannot be missed. It is checked again on every
+    /// wake, a waker only has to notify after changing the state.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        assert!(thread::is_initialized(), "wait before the scheduler started");
+        // disabled across the check and the block so the wake comes after
+        without_interrupts(|| loop {
+            {
+                let mut waiters = self.waiters.lock();
+                if condition() {
+                    return;
+                }
+                waiters.push(thread::current());
+            }
+            thread::block();
+        })
+    }
+
+    /// Wake the thread waiting the longest, return `false` if there is none
+    pub fn notify_one(&self) -> bool {
+        let mut waiters = self.waiters.lock();
+        match waiters.pop() {
+            Some(id) => {
+                thread::unblock(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake every waiting thread and return their number
+    pub fn notify_all(&self) -> usize {
+        let mut waiters = self.waiters.lock();
+        let mut woken = 0;
+        while let Some(id) = waiters.pop() {
+            thread::unblock(id);
+            woken += 1;
+        }
+        woken
+    }
+
+    /// Return the number of waiting threads
+    pub fn len(&self) -> usize {
+        self.waiters.lock().len
+    }
+}
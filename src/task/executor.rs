@@ -0,0 +1,216 @@
+use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use crate::sync::SpinLock;
+use super::{Task, TaskId};
+
+/// Maximum number of tasks of an executor
+pub const MAX_TASKS: usize = 256;
+
+/// Ids of the tasks to poll, in wake order.
+///
+/// Wakers push from interrupt handlers, so the queue has a fixed size and
+/// its lock is only taken with interrupts disabled. A task is queued at
+/// most once, so `MAX_TASKS` slots are enough.
+struct ReadyQueue {
+    ring: SpinLock<Ring>,
+}
+
+struct Ring {
+    ids: [TaskId; MAX_TASKS],
+    head: usize,
+    len: usize,
+}
+
+impl ReadyQueue {
+    fn new() -> Self {
+        ReadyQueue {
+            ring: SpinLock::new(Ring { ids: [TaskId(0); MAX_TASKS], head: 0, len: 0 }),
+        }
+    }
+
+    fn push(&self, id: TaskId) {
+        without_interrupts(|| {
+            let mut ring = self.ring.lock();
+            assert!(ring.len < MAX_TASKS, "ready queue overflow");
+            let tail = (ring.head + ring.len) % MAX_TASKS;
+            ring.ids[tail] = id;
+            ring.len += 1;
+        });
+    }
+
+    fn pop(&self) -> Option<TaskId> {
+        without_interrupts(|| {
+            let mut ring = self.ring.lock();
+            if ring.len == 0 {
+                return None;
+            }
+            let id = ring.ids[ring.head];
+            ring.head = (ring.head + 1) % MAX_TASKS;
+            ring.len -= 1;
+            Some(id)
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        without_interrupts(|| self.ring.lock().len == 0)
+    }
+}
+
+/// Queues its task when woken, once until the task is polled again
+struct TaskWaker {
+    id: TaskId,
+    /// Set while the task is in the ready queue, and for good once it
+    /// completed so that late wakes are ignored
+    queued: AtomicBool,
+    ready: Arc<ReadyQueue>,
+}
+
+impl TaskWaker {
+    fn wake_task(&self) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.ready.push(self.id);
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
+
+/// Spawns tasks on an executor from inside its tasks
+#[derive(Clone)]
+pub struct Spawner {
+    spawned: Rc<RefCell<Vec<Task>>>,
+}
+
+impl Spawner {
+    /// Start running `future` once the current task yields
+    pub fn spawn(&self, future: impl Future<Output = ()> + 'static) -> TaskId {
+        let task = Task::new(future);
+        let id = task.id();
+        self.spawned.borrow_mut().push(task);
+        id
+    }
+}
+
+/// Polls its tasks when they are woken, halting the CPU when none is ready
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    wakers: BTreeMap<TaskId, (Arc<TaskWaker>, Waker)>,
+    ready: Arc<ReadyQueue>,
+    spawner: Spawner,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            wakers: BTreeMap::new(),
+            ready: Arc::new(ReadyQueue::new()),
+            spawner: Spawner { spawned: Rc::new(RefCell::new(Vec::new())) },
+        }
+    }
+
+    pub fn spawner(&self) -> Spawner {
+        self.spawner.clone()
+    }
+
+    /// Start running `future`, it is first polled by the next `run*` call
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + 'static) -> TaskId {
+        self.spawner.spawn(future)
+    }
+
+    /// Return the number of tasks that did not complete
+    pub fn len(&self) -> usize {
+        self.tasks.len() + self.spawner.spawned.borrow().len()
+    }
+
+    /// Take the tasks spawned since the last call and queue them
+    fn admit_spawned(&mut self) {
+        let spawned: Vec<Task> = self.spawner.spawned.borrow_mut().drain(..).collect();
+        for task in spawned {
+            assert!(self.tasks.len() < MAX_TASKS, "more than {} tasks", MAX_TASKS);
+            let id = task.id();
+            let task_waker = Arc::new(TaskWaker {
+                id,
+                queued: AtomicBool::new(true),
+                ready: self.ready.clone(),
+            });
+            let waker = Waker::from(task_waker.clone());
+            self.tasks.insert(id, task);
+            self.wakers.insert(id, (task_waker, waker));
+            self.ready.push(id);
+        }
+    }
+
+    /// Poll every ready task once, and the tasks they wake or spawn
+    fn run_ready(&mut self) {
+        self.admit_spawned();
+        while let Some(id) = self.ready.pop() {
+            let task = match self.tasks.get_mut(&id) {
+                Some(task) => task,
+                None => continue,
+            };
+            let (task_waker, waker) = &self.wakers[&id];
+            // a wake during the poll queues the task again
+            task_waker.queued.store(false, Ordering::Release);
+            let mut context = Context::from_waker(waker);
+            if let Poll::Ready(()) = task.poll(&mut context) {
+                task_waker.queued.store(true, Ordering::Release);
+                self.tasks.remove(&id);
+                self.wakers.remove(&id);
+            }
+            self.admit_spawned();
+        }
+    }
+
+    /// Halt until an interrupt if no task is ready
+    fn sleep_if_idle(&self) {
+        // disabled first so that a wake between the check and `hlt` is
+        // not lost, `enable_and_hlt` only lets interrupts in once halted
+        interrupts::disable();
+        if self.ready.is_empty() && self.spawner.spawned.borrow().is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+
+    /// Run the tasks forever
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Run until every task completed
+    pub fn run_until_complete(&mut self) {
+        loop {
+            self.run_ready();
+            if self.len() == 0 {
+                return;
+            }
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Run until no task is ready, without waiting for interrupts
+    pub fn run_until_idle(&mut self) {
+        self.run_ready();
+    }
+}
@@ -0,0 +1,64 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Bytes a `ByteQueue` holds before new ones are dropped
+pub const QUEUE_SIZE: usize = 128;
+
+/// Bytes from a device interrupt, filled by the handler and drained by a
+/// single reader at a time, without locks or allocation
+pub struct ByteQueue {
+    buffer: UnsafeCell<[u8; QUEUE_SIZE]>,
+    /// Total bytes pushed, the next one goes at `head % QUEUE_SIZE`
+    head: AtomicUsize,
+    /// Total bytes popped
+    tail: AtomicUsize,
+    /// Bytes dropped because the queue was full
+    overflows: AtomicU64,
+}
+
+// the handler only writes the slot past `head`, the reader only reads the
+// slots before it, and `head` is published after the write
+unsafe impl Sync for ByteQueue {}
+
+impl ByteQueue {
+    pub const fn new() -> Self {
+        ByteQueue {
+            buffer: UnsafeCell::new([0; QUEUE_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            overflows: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue `byte`, or count it as an overflow and return `false` if full
+    pub fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(self.tail.load(Ordering::Acquire)) == QUEUE_SIZE {
+            self.overflows.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        unsafe { (*self.buffer.get())[head % QUEUE_SIZE] = byte };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Must only be called by one reader at a time
+    pub fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = unsafe { (*self.buffer.get())[tail % QUEUE_SIZE] };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+
+    pub fn len(&self) -> usize {
+        self.head.load(Ordering::Acquire).wrapping_sub(self.tail.load(Ordering::Acquire))
+    }
+
+    /// Return the number of bytes dropped
+    pub fn overflows(&self) -> u64 {
+        self.overflows.load(Ordering::Relaxed)
+    }
+}
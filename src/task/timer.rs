@@ -0,0 +1,119 @@
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use lazy_static::lazy_static;
+use spin::Once;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::sync::SpinLock;
+use crate::time;
+
+/// Maximum number of sleeping futures woken by the timer, more are polled
+/// again right away until they are due
+pub const MAX_SLEEPERS: usize = 64;
+
+struct Sleeper {
+    /// `Sleep::id` of the owner, the slot may be reused once the timer took it
+    id: u64,
+    deadline: u64,
+    waker: Waker,
+}
+
+lazy_static! {
+    /// Sleeping futures, allocated up front as the timer interrupt takes
+    /// wakers out of it. Only locked with interrupts disabled.
+    static ref SLEEPERS: SpinLock<Vec<Option<Sleeper>>> = SpinLock::new((0..MAX_SLEEPERS).map(|_| None).collect());
+}
+
+static WAKE_SLEEPERS: Once<()> = Once::new();
+
+/// Wake the sleepers that are due, from the timer interrupt
+fn wake_sleepers() {
+    let now = time::uptime_nanos();
+    let mut sleepers = match SLEEPERS.try_lock() {
+        Some(sleepers) => sleepers,
+        None => return,
+    };
+    for slot in sleepers.iter_mut() {
+        if slot.as_ref().map_or(false, |sleeper| sleeper.deadline <= now) {
+            if let Some(sleeper) = slot.take() {
+                sleeper.waker.wake();
+            }
+        }
+    }
+}
+
+/// Complete after `duration`
+pub fn sleep(duration: Duration) -> Sleep {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    WAKE_SLEEPERS.call_once(|| {
+        time::register_periodic(time::tick_period(), wake_sleepers).expect("no timer for the sleepers");
+    });
+    Sleep {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        deadline: time::uptime_nanos() + duration.as_nanos() as u64,
+        slot: None,
+    }
+}
+
+pub struct Sleep {
+    id: u64,
+    /// Uptime in nanoseconds to complete at
+    deadline: u64,
+    /// Index in `SLEEPERS` of the last registered waker
+    slot: Option<usize>,
+}
+
+impl Sleep {
+    fn owns(&self, slot: &Option<Sleeper>) -> bool {
+        slot.as_ref().map_or(false, |sleeper| sleeper.id == self.id)
+    }
+
+    fn unregister(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            // the waker is dropped here rather than in the interrupt
+            let sleeper = without_interrupts(|| {
+                let mut sleepers = SLEEPERS.lock();
+                if self.owns(&sleepers[slot]) { sleepers[slot].take() } else { None }
+            });
+            drop(sleeper);
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if time::uptime_nanos() >= self.deadline {
+            self.unregister();
+            return Poll::Ready(());
+        }
+
+        let (id, deadline) = (self.id, self.deadline);
+        let registered = without_interrupts(|| {
+            let mut sleepers = SLEEPERS.lock();
+            // the timer frees the slot when it wakes us
+            let slot = self.slot.filter(|&slot| self.owns(&sleepers[slot]))
+                .or_else(|| sleepers.iter().position(|slot| slot.is_none()))?;
+            let waker = cx.waker().clone();
+            Some((slot, sleepers[slot].replace(Sleeper { id, deadline, waker })))
+        });
+        match registered {
+            Some((slot, replaced)) => {
+                self.slot = Some(slot);
+                drop(replaced);
+            }
+            None => cx.waker().wake_by_ref(),
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
@@ -0,0 +1,61 @@
+use x86_64::VirtAddr;
+
+global_asm!(r#"
+.intel_syntax noprefix
+
+// switch_context(save: *mut u64, load: u64)
+//
+// Push the callee-saved registers, store the stack pointer in `*save`, then
+// load the stack pointer `load` and pop the registers of the thread saved
+// there. The caller-saved registers are already spilled by the compiler.
+.global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+.att_syntax
+"#);
+
+extern "C" {
+    /// Save the current thread's registers and stack pointer to `save` and
+    /// resume the thread whose stack pointer is `load`
+    pub fn switch_context(save: *mut u64, load: u64);
+}
+
+/// Callee-saved registers pushed by `switch_context`
+const SAVED_REGISTERS: u64 = 6;
+
+/// Prepare the stack below `top` so that `switch_context` resumes into
+/// `entry`, and return the stack pointer to load.
+///
+/// This function is unsafe because `top` must be the top of a mapped stack
+/// that nothing else uses.
+pub unsafe fn initial_stack(top: VirtAddr, entry: extern "C" fn() -> !) -> u64 {
+    // `entry` is reached by `ret`, so it sees the stack as if called: the
+    // return address slot just above 16-byte alignment. The fake return
+    // address is null, and so are the popped registers, rbp included, which
+    // ends backtraces there.
+    let top = top.as_u64() & !0xf;
+    let return_address = (top - 8) as *mut u64;
+    let entry_address = (top - 16) as *mut u64;
+    return_address.write(0);
+    entry_address.write(entry as u64);
+    let rsp = top - 16 - SAVED_REGISTERS * 8;
+    for register in 0..SAVED_REGISTERS {
+        (rsp as *mut u64).add(register as usize).write(0);
+    }
+    rsp
+}
@@ -0,0 +1,471 @@
+pub mod context;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use crate::memory::stack::{self, KernelStack, StackError};
+use crate::println;
+use crate::time::{self, tsc};
+
+/// Maximum number of threads, finished ones included until joined
+pub const MAX_THREADS: usize = 32;
+
+/// Pages of the stack of a spawned thread
+pub const THREAD_STACK_PAGES: usize = 16;
+
+/// Timer ticks a thread runs before the next ready one is switched to
+pub const TIME_SLICE: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    /// Until the uptime in nanoseconds
+    Sleeping(u64),
+    /// Until the thread finished
+    Joining(ThreadId),
+    /// Until `unblock`, queued in a `sync::WaitQueue`
+    Blocked,
+    /// Waiting to be joined
+    Finished,
+}
+
+#[derive(Debug)]
+pub enum SpawnError {
+    /// The thread table is full
+    Full,
+    Stack(StackError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// No such thread, or it was already joined
+    NoSuchThread,
+    /// A thread cannot wait for itself
+    SelfJoin,
+}
+
+/// A row of the thread table
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: State,
+    pub cpu_time: Duration,
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: State,
+    /// `None` for the thread `init` was called from, which keeps its stack
+    stack: Option<KernelStack>,
+    /// Stack pointer saved by `switch_context` while not running
+    rsp: u64,
+    /// Taken by the thread when it first runs
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// TSC cycles spent running
+    cpu_cycles: u64,
+    /// Only run when no other thread is ready
+    idle: bool,
+}
+
+struct Scheduler {
+    threads: Vec<Option<Thread>>,
+    /// Slot of the running thread
+    current: usize,
+    next_id: u64,
+    /// Ticks since `current` was switched to
+    slice_ticks: u64,
+    /// TSC when `current` was switched to
+    slice_start: u64,
+}
+
+impl Scheduler {
+    fn thread(&mut self, slot: usize) -> &mut Thread {
+        self.threads[slot].as_mut().expect("empty thread slot")
+    }
+
+    fn slot_of(&self, id: ThreadId) -> Option<usize> {
+        self.threads.iter().position(|thread| thread.as_ref().map_or(false, |thread| thread.id == id))
+    }
+
+    fn wake_sleepers(&mut self, now: u64) {
+        for thread in self.threads.iter_mut().flatten() {
+            if let State::Sleeping(until) = thread.state {
+                if until <= now {
+                    thread.state = State::Ready;
+                }
+            }
+        }
+    }
+
+    /// Return the first ready thread after `current`, round-robin, falling
+    /// back to `current` if it stays ready and then to the idle thread
+    fn pick_next(&self) -> usize {
+        let count = self.threads.len();
+        let ready = |slot: usize| self.threads[slot].as_ref()
+            .map_or(false, |thread| thread.state == State::Ready && !thread.idle);
+        (1..=count).map(|offset| (self.current + offset) % count)
+            .find(|&slot| ready(slot))
+            .or_else(|| self.threads.iter().position(|thread| thread.as_ref().map_or(false, |thread| thread.idle)))
+            .expect("no idle thread")
+    }
+
+    /// Return whether a thread other than the current and idle ones is ready
+    fn others_ready(&self) -> bool {
+        self.threads.iter().enumerate().any(|(slot, thread)| {
+            slot != self.current && thread.as_ref().map_or(false, |thread| thread.state == State::Ready && !thread.idle)
+        })
+    }
+}
+
+lazy_static! {
+    /// The thread table, allocated up front as the timer interrupt switches
+    /// threads. Only locked with interrupts disabled.
+    static ref SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+        threads: (0..MAX_THREADS).map(|_| None).collect(),
+        current: 0,
+        next_id: 0,
+        slice_ticks: 0,
+        slice_start: 0,
+    });
+}
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Id of the running thread, set with `SCHEDULER.current` and read without
+/// taking the scheduler lock
+static CURRENT: AtomicU64 = AtomicU64::new(0);
+
+/// Depth of `preempt_disable` calls, the timer does not switch threads
+/// while it is not 0
+static PREEMPT_DISABLED: AtomicUsize = AtomicUsize::new(0);
+
+/// Make the caller the first thread, named `name`, and start the idle thread.
+///
+/// Must be called once, after `stack::init` and `tsc::init`.
+pub fn init(name: &'static str) {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let id = ThreadId(scheduler.next_id);
+        scheduler.next_id += 1;
+        scheduler.threads[0] = Some(Thread {
+            id,
+            name,
+            state: State::Running,
+            stack: None,
+            rsp: 0,
+            entry: None,
+            cpu_cycles: 0,
+            idle: false,
+        });
+        scheduler.current = 0;
+        CURRENT.store(id.0, Ordering::Relaxed);
+        scheduler.slice_start = tsc::rdtsc();
+    });
+    let idle = create("idle", Box::new(idle_loop)).expect("no memory for the idle thread");
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let slot = scheduler.slot_of(idle).unwrap();
+        scheduler.thread(slot).idle = true;
+    });
+    INITIALIZED.store(true, Ordering::Release);
+}
+
+fn idle_loop() {
+    loop {
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Start running `entry` in a new thread named `name`.
+///
+/// The thread is ready right away and runs on its own guarded stack. Once
+/// it returns, its slot and stack are kept until it is joined.
+pub fn spawn<F>(name: &'static str, entry: F) -> Result<ThreadId, SpawnError>
+    where F: FnOnce() + Send + 'static
+{
+    create(name, Box::new(entry))
+}
+
+fn create(name: &'static str, entry: Box<dyn FnOnce() + Send>) -> Result<ThreadId, SpawnError> {
+    let stack = stack::allocate(THREAD_STACK_PAGES, name).map_err(SpawnError::Stack)?;
+    let rsp = unsafe { context::initial_stack(stack.top, thread_entry) };
+    let mut thread = Thread {
+        id: ThreadId(0),
+        name,
+        state: State::Ready,
+        stack: Some(stack),
+        rsp,
+        entry: Some(entry),
+        cpu_cycles: 0,
+        idle: false,
+    };
+    let spawned = without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let slot = match scheduler.threads.iter().position(|thread| thread.is_none()) {
+            Some(slot) => slot,
+            None => return Err(thread),
+        };
+        thread.id = ThreadId(scheduler.next_id);
+        scheduler.next_id += 1;
+        let id = thread.id;
+        scheduler.threads[slot] = Some(thread);
+        Ok(id)
+    });
+    spawned.map_err(|thread| {
+        drop(thread);
+        unsafe { stack::free(stack) };
+        SpawnError::Full
+    })
+}
+
+/// First code run by a spawned thread, `switch_context` returns into it
+extern "C" fn thread_entry() -> ! {
+    let entry = without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        scheduler.thread(current).entry.take()
+    });
+    // switched to with interrupts disabled
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit()
+}
+
+/// Give the CPU to the next ready thread, the current one gets `state`.
+///
+/// Must be called with interrupts disabled. Returns when the current thread
+/// is switched to again, or right away before `init`.
+fn switch(state: State) {
+    if !is_initialized() {
+        return;
+    }
+    debug_assert!(preemptible(), "thread switch with preemption disabled");
+    let (save, load) = {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.wake_sleepers(time::uptime_nanos());
+
+        let now = tsc::rdtsc();
+        let ran = now - scheduler.slice_start;
+        let current = scheduler.current;
+        scheduler.slice_start = now;
+        scheduler.slice_ticks = 0;
+        let thread = scheduler.thread(current);
+        thread.cpu_cycles += ran;
+        thread.state = state;
+
+        let next = scheduler.pick_next();
+        scheduler.thread(next).state = State::Running;
+        if next == current {
+            return;
+        }
+        scheduler.current = next;
+        CURRENT.store(scheduler.thread(next).id.0, Ordering::Relaxed);
+        let save = &mut scheduler.thread(current).rsp as *mut u64;
+        (save, scheduler.thread(next).rsp)
+    };
+    // the slot of the current thread is only freed once it finished and was
+    // switched away from, so `save` stays valid
+    unsafe { context::switch_context(save, load) };
+}
+
+/// Switch threads if the time slice of the current one is over, or if it
+/// is the idle thread and another one became ready. Nothing is switched
+/// while preemption is disabled, the slice ends on a later tick.
+///
+/// Called from the timer interrupt, after `time::tick`.
+pub fn preempt() {
+    if !is_initialized() {
+        return;
+    }
+    let switch_now = {
+        let mut scheduler = match SCHEDULER.try_lock() {
+            Some(scheduler) => scheduler,
+            None => return,
+        };
+        scheduler.slice_ticks += 1;
+        scheduler.wake_sleepers(time::uptime_nanos());
+        let current = scheduler.current;
+        let idle = scheduler.thread(current).idle;
+        preemptible() && scheduler.others_ready() && (idle || scheduler.slice_ticks >= TIME_SLICE)
+    };
+    if switch_now {
+        switch(State::Ready);
+    }
+}
+
+/// Block the current thread until `unblock` is called with its id.
+///
+/// Must be called with interrupts disabled, once the thread is queued where
+/// its waker finds it, so that the wake cannot come first.
+pub fn block() {
+    switch(State::Blocked);
+}
+
+/// Make the blocked thread `id` ready, return `false` if it is not blocked.
+///
+/// May be called from interrupt handlers.
+pub fn unblock(id: ThreadId) -> bool {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        match scheduler.slot_of(id) {
+            Some(slot) if scheduler.thread(slot).state == State::Blocked => {
+                scheduler.thread(slot).state = State::Ready;
+                true
+            }
+            _ => false,
+        }
+    })
+}
+
+/// Keep the timer from switching away from the current thread until the
+/// matching `preempt_enable`. Calls nest.
+///
+/// Held by the spinlocks that do not disable interrupts, so that a thread
+/// never spins on a lock whose holder was switched away from.
+pub fn preempt_disable() {
+    PREEMPT_DISABLED.fetch_add(1, Ordering::Acquire);
+}
+
+/// Undo a `preempt_disable`
+pub fn preempt_enable() {
+    let depth = PREEMPT_DISABLED.fetch_sub(1, Ordering::Release);
+    debug_assert!(depth > 0, "preempt_enable without preempt_disable");
+}
+
+/// Return whether the timer may switch away from the current thread
+pub fn preemptible() -> bool {
+    PREEMPT_DISABLED.load(Ordering::Relaxed) == 0
+}
+
+/// Let the other ready threads run before continuing, does nothing before
+/// `init`
+pub fn yield_now() {
+    if !is_initialized() {
+        return;
+    }
+    without_interrupts(|| switch(State::Ready));
+}
+
+/// Block the current thread for at least `duration`, halting in place
+/// before `init`
+pub fn sleep(duration: Duration) {
+    if !is_initialized() {
+        time::sleep(duration);
+        return;
+    }
+    let until = time::uptime_nanos() + duration.as_nanos() as u64;
+    without_interrupts(|| {
+        while time::uptime_nanos() < until {
+            switch(State::Sleeping(until));
+        }
+    });
+}
+
+/// Block until the thread `id` finished, then free its slot and stack
+pub fn join(id: ThreadId) -> Result<(), JoinError> {
+    if id == current() {
+        return Err(JoinError::SelfJoin);
+    }
+    let thread = without_interrupts(|| loop {
+        let finished = {
+            let mut scheduler = SCHEDULER.lock();
+            let slot = scheduler.slot_of(id).ok_or(JoinError::NoSuchThread)?;
+            if scheduler.thread(slot).state == State::Finished {
+                scheduler.threads[slot].take()
+            } else {
+                None
+            }
+        };
+        match finished {
+            Some(thread) => return Ok(thread),
+            None => switch(State::Joining(id)),
+        }
+    })?;
+    if let Some(stack) = thread.stack {
+        // the thread was switched away from for good when it finished
+        unsafe { stack::free(stack) };
+    }
+    Ok(())
+}
+
+/// Finish the current thread and wake the threads joining it
+pub fn exit() -> ! {
+    interrupts::disable();
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        let id = scheduler.thread(current).id;
+        for thread in scheduler.threads.iter_mut().flatten() {
+            if thread.state == State::Joining(id) {
+                thread.state = State::Ready;
+            }
+        }
+    }
+    switch(State::Finished);
+    unreachable!("finished thread switched to");
+}
+
+/// Return whether `init` was called
+pub fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::Acquire)
+}
+
+/// Return the id of the running thread, without taking the scheduler lock
+pub fn current() -> ThreadId {
+    ThreadId(CURRENT.load(Ordering::Relaxed))
+}
+
+/// Return the thread table, with the CPU time of each thread
+pub fn threads() -> Vec<ThreadInfo> {
+    let mut threads = Vec::with_capacity(MAX_THREADS);
+    without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        let running = tsc::rdtsc() - scheduler.slice_start;
+        for (slot, thread) in scheduler.threads.iter().enumerate() {
+            if let Some(thread) = thread {
+                let cycles = if slot == scheduler.current { thread.cpu_cycles + running } else { thread.cpu_cycles };
+                threads.push(ThreadInfo {
+                    id: thread.id,
+                    name: thread.name,
+                    state: thread.state,
+                    cpu_time: tsc::cycles_to_duration(cycles),
+                });
+            }
+        }
+    });
+    threads
+}
+
+/// Print the thread table
+pub fn dump() {
+    println!("{:>4} {:<16} {:<16} {:>12}", "id", "name", "state", "cpu ms");
+    for thread in threads() {
+        let state = match thread.state {
+            State::Ready => "ready",
+            State::Running => "running",
+            State::Sleeping(_) => "sleeping",
+            State::Joining(_) => "joining",
+            State::Blocked => "blocked",
+            State::Finished => "finished",
+        };
+        println!("{:>4} {:<16} {:<16} {:>12}", thread.id.as_u64(), thread.name, state, thread.cpu_time.as_millis());
+    }
+}
@@ -0,0 +1,68 @@
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::PageTableFlags;
+use crate::acpi;
+use crate::memory::vmalloc;
+
+// registers, offsets from the base
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0f0;
+const ENABLE: u64 = 1;
+
+/// Virtual address of the registers, 0 without an HPET
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// Length of a counter tick in femtoseconds
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+/// Bits of the main counter, which wraps at 32 bits on some HPETs
+static COUNTER_MASK: AtomicU64 = AtomicU64::new(u64::MAX);
+
+unsafe fn read(register: usize) -> u64 {
+    ptr::read_volatile((BASE.load(Ordering::Relaxed) as usize + register) as *const u64)
+}
+
+unsafe fn write(register: usize, value: u64) {
+    ptr::write_volatile((BASE.load(Ordering::Relaxed) as usize + register) as *mut u64, value);
+}
+
+/// Map the HPET described by ACPI and start its main counter.
+///
+/// Returns `false` if there is none. Needs `acpi::init` and `vmalloc`.
+pub fn init() -> bool {
+    let hpet = match acpi::tables().and_then(|acpi| acpi.hpet) {
+        Some(hpet) => hpet,
+        None => return false,
+    };
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let base = match vmalloc::ioremap(hpet.address, 1024, flags, "hpet") {
+        Ok(base) => base,
+        Err(_) => return false,
+    };
+
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+    COUNTER_MASK.store(if hpet.counter_64_bits { u64::MAX } else { u32::MAX as u64 }, Ordering::Relaxed);
+    unsafe {
+        PERIOD_FS.store(read(CAPABILITIES) >> 32, Ordering::Relaxed);
+        write(CONFIGURATION, read(CONFIGURATION) | ENABLE);
+    }
+    true
+}
+
+pub fn is_available() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Return the counter frequency in Hz
+pub fn frequency() -> u64 {
+    1_000_000_000_000_000 / PERIOD_FS.load(Ordering::Relaxed).max(1)
+}
+
+/// Return the main counter, it goes up and wraps if it is 32 bits wide
+pub fn counter() -> u64 {
+    unsafe { read(MAIN_COUNTER) & COUNTER_MASK.load(Ordering::Relaxed) }
+}
+
+/// Return the counter ticks from `start` to `end`, across one wrap
+pub fn ticks_between(start: u64, end: u64) -> u64 {
+    end.wrapping_sub(start) & COUNTER_MASK.load(Ordering::Relaxed)
+}
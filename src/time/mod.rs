@@ -0,0 +1,208 @@
+pub mod pit;
+pub mod rtc;
+pub mod hpet;
+pub mod tsc;
+
+use core::sync::atomic::{spin_loop_hint, AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use crate::sync::SpinLock;
+
+/// Timer interrupts per second unless `set_frequency` says otherwise
+pub const DEFAULT_FREQUENCY: u32 = 1000;
+
+/// Maximum number of callbacks registered at once
+pub const MAX_TIMERS: usize = 32;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+/// Number of timer interrupts since interrupts were enabled
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// PIT input clocks elapsed at the last tick, exact across frequency changes
+static PIT_CYCLES: AtomicU64 = AtomicU64::new(0);
+/// Input clocks per tick, 65536 is what the BIOS programs
+static DIVISOR: AtomicU32 = AtomicU32::new(65536);
+
+/// Program the PIT to tick `frequency` times per second
+pub fn init(frequency: u32) {
+    set_frequency(frequency);
+}
+
+/// Change the tick rate, the closest the PIT can do to `frequency`
+pub fn set_frequency(frequency: u32) {
+    let divisor = pit::divisor_for(frequency);
+    without_interrupts(|| {
+        DIVISOR.store(divisor, Ordering::Relaxed);
+        pit::set_divisor(divisor);
+    });
+}
+
+/// Return the actual tick rate in Hz, rounded
+pub fn frequency() -> u32 {
+    let divisor = DIVISOR.load(Ordering::Relaxed);
+    (pit::PIT_FREQUENCY + divisor / 2) / divisor
+}
+
+/// Return the time between two ticks
+pub fn tick_period() -> Duration {
+    cycles_to_duration(DIVISOR.load(Ordering::Relaxed) as u64)
+}
+
+/// Return the number of timer interrupts since interrupts were enabled
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+fn cycles_to_duration(cycles: u64) -> Duration {
+    Duration::from_nanos((cycles as u128 * NANOS_PER_SECOND / pit::PIT_FREQUENCY as u128) as u64)
+}
+
+/// Return the time since interrupts were enabled, with the resolution of
+/// a tick. It never goes backwards.
+pub fn uptime() -> Duration {
+    cycles_to_duration(PIT_CYCLES.load(Ordering::Relaxed))
+}
+
+/// Return `uptime` in nanoseconds
+pub fn uptime_nanos() -> u64 {
+    uptime().as_nanos() as u64
+}
+
+/// Spin until `duration` has passed, for short delays or when the caller
+/// must not halt. Interrupts must be enabled.
+pub fn busy_wait(duration: Duration) {
+    assert!(interrupts::are_enabled(), "busy_wait with interrupts disabled");
+    let deadline = uptime() + duration;
+    while uptime() < deadline {
+        spin_loop_hint();
+    }
+}
+
+/// Halt until `duration` has passed. Interrupts must be enabled.
+pub fn sleep(duration: Duration) {
+    assert!(interrupts::are_enabled(), "sleep with interrupts disabled");
+    let deadline = uptime() + duration;
+    while uptime() < deadline {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Handle returned by `register_timeout` and `register_periodic`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    index: usize,
+    generation: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// `MAX_TIMERS` callbacks are registered
+    Full,
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    callback: fn(),
+    /// Uptime in nanoseconds of the next call
+    deadline: u64,
+    /// Time between calls of a periodic timer, 0 for a one-shot
+    period: u64,
+    /// Tells the timer apart from the earlier ones of its slot
+    generation: u64,
+}
+
+/// Registered callbacks, taken by the timer interrupt
+static TIMERS: SpinLock<[Option<Timer>; MAX_TIMERS]> = SpinLock::new([None; MAX_TIMERS]);
+
+/// Generation of the next registered timer
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Ticks that found `TIMERS` locked and left their run to the next tick
+static DEFERRED_RUNS: AtomicU64 = AtomicU64::new(0);
+
+fn register(callback: fn(), delay: Duration, period: Duration) -> Result<TimerId, TimerError> {
+    let timer = Timer {
+        callback,
+        deadline: uptime_nanos() + delay.as_nanos() as u64,
+        period: period.as_nanos() as u64,
+        generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
+    };
+    without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let (index, slot) = timers.iter_mut().enumerate().find(|(_, slot)| slot.is_none()).ok_or(TimerError::Full)?;
+        *slot = Some(timer);
+        Ok(TimerId { index, generation: timer.generation })
+    })
+}
+
+/// Call `callback` once, from the timer interrupt, after `delay`
+pub fn register_timeout(delay: Duration, callback: fn()) -> Result<TimerId, TimerError> {
+    register(callback, delay, Duration::from_secs(0))
+}
+
+/// Call `callback` every `period`, from the timer interrupt, at most once
+/// per tick
+pub fn register_periodic(period: Duration, callback: fn()) -> Result<TimerId, TimerError> {
+    let period = period.max(Duration::from_nanos(1));
+    register(callback, period, period)
+}
+
+/// Stop calling the callback of `id`. Returns `false` if it was a one-shot
+/// that already ran or was already cancelled, even if its slot was reused.
+pub fn cancel(id: TimerId) -> bool {
+    without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let slot = &mut timers[id.index];
+        let registered = slot.map_or(false, |timer| timer.generation == id.generation);
+        if registered {
+            *slot = None;
+        }
+        registered
+    })
+}
+
+/// Return the number of ticks that left the callbacks due to the next one
+pub fn deferred_runs() -> u64 {
+    DEFERRED_RUNS.load(Ordering::Relaxed)
+}
+
+/// Count a timer interrupt and run the callbacks that are due.
+///
+/// If the timers are locked by the interrupted code, the run is counted as
+/// deferred and the callbacks due stay registered, the next tick calls them.
+///
+/// Called from the timer interrupt handler.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    PIT_CYCLES.fetch_add(DIVISOR.load(Ordering::Relaxed) as u64, Ordering::Relaxed);
+
+    // run the callbacks without the lock, so they can register timers
+    let now = uptime_nanos();
+    let mut due: [Option<fn()>; MAX_TIMERS] = [None; MAX_TIMERS];
+    {
+        let mut timers = match TIMERS.try_lock() {
+            Some(timers) => timers,
+            None => {
+                DEFERRED_RUNS.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        for (slot, due) in timers.iter_mut().zip(due.iter_mut()) {
+            if let Some(timer) = slot {
+                if timer.deadline > now {
+                    continue;
+                }
+                *due = Some(timer.callback);
+                if timer.period == 0 {
+                    *slot = None;
+                } else {
+                    // skip the periods missed while interrupts were disabled
+                    timer.deadline = (timer.deadline + timer.period).max(now + 1);
+                }
+            }
+        }
+    }
+    for callback in due.iter().flatten() {
+        callback();
+    }
+}
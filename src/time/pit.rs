@@ -0,0 +1,44 @@
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+/// Input clock of the PIT in Hz
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+
+// channel 0, low then high byte, mode 2 (rate generator), binary
+const RATE_GENERATOR: u8 = 0b00_11_010_0;
+// channel 0, latch the count
+const LATCH_COUNT: u8 = 0b00_00_000_0;
+
+/// Return the divisor giving the closest frequency to `frequency`, 65536
+/// for the slowest rate the PIT can do
+pub fn divisor_for(frequency: u32) -> u32 {
+    let frequency = frequency.max(1) as u64;
+    let divisor = (PIT_FREQUENCY as u64 + frequency / 2) / frequency;
+    divisor.max(1).min(65536) as u32
+}
+
+/// Make channel 0 raise IRQ 0 every `divisor` input clocks
+pub fn set_divisor(divisor: u32) {
+    // 0 stands for 65536
+    let reload = divisor as u16;
+    without_interrupts(|| unsafe {
+        Port::<u8>::new(COMMAND).write(RATE_GENERATOR);
+        let mut data = Port::<u8>::new(CHANNEL_0);
+        data.write(reload as u8);
+        data.write((reload >> 8) as u8);
+    });
+}
+
+/// Return the current count of channel 0, it counts down to the next tick
+pub fn read_count() -> u16 {
+    without_interrupts(|| unsafe {
+        Port::<u8>::new(COMMAND).write(LATCH_COUNT);
+        let mut data = Port::<u8>::new(CHANNEL_0);
+        let low = data.read() as u16;
+        let high = data.read() as u16;
+        high << 8 | low
+    })
+}
@@ -0,0 +1,228 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use crate::acpi;
+use crate::interrupts;
+use crate::time;
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+// clock registers
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+/// Where most BIOSes keep the century when ACPI does not say
+const DEFAULT_CENTURY: u8 = 0x32;
+
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const PERIODIC_INTERRUPT: u8 = 1 << 6;
+const PERIODIC_FLAG: u8 = 1 << 6;
+const BINARY: u8 = 1 << 2;
+const HOURS_24: u8 = 1 << 1;
+const PM: u8 = 1 << 7;
+
+/// Wall clock time, UTC as the CMOS clock of a PC is expected to be
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Return the seconds since 1970-01-01 00:00:00
+    pub fn unix_timestamp(&self) -> u64 {
+        // days from the civil calendar, with years starting in March
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = (self.month as i64 + 9) % 12;
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        days as u64 * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    /// Return the date and time `timestamp` seconds after 1970-01-01
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let days = (timestamp / 86400) as i64 + 719_468;
+        let seconds = timestamp % 86400;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+               self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// Unix time in nanoseconds when the uptime was 0, set by `init`
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+/// Periodic interrupts taken since `enable_periodic_interrupt`
+static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(DEFAULT_CENTURY);
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_INDEX).write(register);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+fn write_register(register: u8, value: u8) {
+    unsafe {
+        Port::<u8>::new(CMOS_INDEX).write(register);
+        Port::<u8>::new(CMOS_DATA).write(value);
+    }
+}
+
+pub fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// The clock registers as stored, BCD or binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime([u8; 7]);
+
+fn read_raw() -> RawTime {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+    let century = CENTURY_REGISTER.load(Ordering::Relaxed);
+    let mut raw = [0; 7];
+    for (value, &register) in raw.iter_mut().zip([SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR, century].iter()) {
+        *value = read_register(register);
+    }
+    RawTime(raw)
+}
+
+/// Read the clock, retrying until two reads agree so that no update
+/// happened in between
+pub fn read() -> DateTime {
+    let (raw, status) = without_interrupts(|| {
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, read_register(STATUS_B))
+    });
+
+    let [second, minute, hour, day, month, year, century] = raw.0;
+    let decode = |value| if status & BINARY != 0 { value } else { bcd_to_binary(value) };
+    let mut hour_24 = decode(hour & !PM);
+    if status & HOURS_24 == 0 {
+        // 12 AM is midnight, 12 PM is noon
+        hour_24 %= 12;
+        if hour & PM != 0 {
+            hour_24 += 12;
+        }
+    }
+    // garbage if the register is not the century
+    let century = match decode(century) {
+        century @ 19..=99 => century as u16,
+        _ => 20,
+    };
+
+    DateTime {
+        year: century * 100 + decode(year) as u16,
+        month: decode(month),
+        day: decode(day),
+        hour: hour_24,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+/// Read the clock once to anchor `now` to the uptime, to the second.
+///
+/// Uses the century register given by the ACPI FADT if `acpi::init` found one.
+pub fn init() -> DateTime {
+    if let Some(century) = acpi::tables().and_then(|acpi| acpi.fadt).map(|fadt| fadt.century).filter(|&c| c != 0) {
+        CENTURY_REGISTER.store(century, Ordering::Relaxed);
+    }
+
+    let now = read();
+    let unix_nanos = now.unix_timestamp() * 1_000_000_000;
+    BOOT_TIME.store(unix_nanos.saturating_sub(time::uptime_nanos()), Ordering::Relaxed);
+    now
+}
+
+/// Return the time since 1970-01-01, the clock read by `init` advanced by
+/// the uptime
+pub fn unix_time() -> Duration {
+    Duration::from_nanos(BOOT_TIME.load(Ordering::Relaxed) + time::uptime_nanos())
+}
+
+/// Return the current date and time
+pub fn now() -> DateTime {
+    DateTime::from_unix_timestamp(unix_time().as_secs())
+}
+
+/// Make the clock raise IRQ 8 at 32768 >> (rate - 1) Hz, `rate` is clamped
+/// to 3 (8192 Hz) ..= 15 (2 Hz)
+pub fn enable_periodic_interrupt(rate: u8) {
+    let rate = rate.max(3).min(15);
+    without_interrupts(|| {
+        let a = read_register(STATUS_A);
+        write_register(STATUS_A, (a & 0xf0) | rate);
+        let b = read_register(STATUS_B);
+        write_register(STATUS_B, b | PERIODIC_INTERRUPT);
+        // an unread status C blocks further interrupts
+        read_register(STATUS_C);
+    });
+    interrupts::unmask_pic_irq(interrupts::RTC_IRQ);
+}
+
+pub fn disable_periodic_interrupt() {
+    without_interrupts(|| {
+        let b = read_register(STATUS_B);
+        write_register(STATUS_B, b & !PERIODIC_INTERRUPT);
+    });
+}
+
+/// Return the number of periodic interrupts taken
+pub fn interrupt_count() -> u64 {
+    INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// Acknowledge the clock interrupt.
+///
+/// Called from the RTC interrupt handler.
+pub fn handle_interrupt() {
+    if read_register(STATUS_C) & PERIODIC_FLAG != 0 {
+        INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    }
+}
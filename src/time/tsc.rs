@@ -0,0 +1,156 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::hlt;
+use crate::println;
+use crate::time::{self, hpet};
+
+const EXTENDED_FEATURES: u32 = 0x8000_0000;
+const ADVANCED_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const INVARIANT_TSC: u32 = 1 << 8;
+
+/// How long `init` measures the TSC against the reference clock
+const CALIBRATION_TIME: Duration = Duration::from_millis(50);
+
+/// TSC increments per second, 0 until `init`
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// The clock the TSC frequency was measured against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reference {
+    Hpet,
+    Pit,
+}
+
+/// Return the time stamp counter
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Return whether the TSC runs at a constant rate in every power state,
+/// otherwise its frequency may change after calibration
+pub fn is_invariant() -> bool {
+    unsafe {
+        __cpuid(EXTENDED_FEATURES).eax >= ADVANCED_POWER_MANAGEMENT
+            && __cpuid(ADVANCED_POWER_MANAGEMENT).edx & INVARIANT_TSC != 0
+    }
+}
+
+/// Measure the TSC frequency against the HPET, or the PIT ticks when
+/// there is no HPET, and return it with the reference used.
+///
+/// Interrupts must be enabled. Needs `acpi::init` to find the HPET.
+/// Warns when the TSC is not invariant, timestamps then drift whenever the
+/// CPU changes frequency or power state.
+pub fn init() -> (u64, Reference) {
+    if !is_invariant() {
+        println!("warning: the TSC is not invariant, timestamps may drift");
+    }
+    let (frequency, reference) = if hpet::init() {
+        (calibrate_hpet(), Reference::Hpet)
+    } else {
+        (calibrate_pit(), Reference::Pit)
+    };
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    (frequency, reference)
+}
+
+fn calibrate_hpet() -> u64 {
+    let hpet_frequency = hpet::frequency();
+    let cycles = hpet_frequency * CALIBRATION_TIME.as_millis() as u64 / 1000;
+
+    let start_counter = hpet::counter();
+    let start = rdtsc();
+    let mut elapsed = 0;
+    while elapsed < cycles {
+        elapsed = hpet::ticks_between(start_counter, hpet::counter());
+    }
+    let end = rdtsc();
+    ((end - start) as u128 * hpet_frequency as u128 / elapsed as u128) as u64
+}
+
+/// Count TSC cycles between two timer interrupts `CALIBRATION_TIME` apart,
+/// both read right after an interrupt so that the ticks are whole
+fn calibrate_pit() -> u64 {
+    let wait_tick = || {
+        let ticks = time::ticks();
+        while time::ticks() == ticks {
+            hlt();
+        }
+    };
+
+    wait_tick();
+    let start_uptime = time::uptime();
+    let start = rdtsc();
+    while time::uptime() - start_uptime < CALIBRATION_TIME {
+        wait_tick();
+    }
+    let end = rdtsc();
+    let elapsed = time::uptime() - start_uptime;
+    ((end - start) as u128 * 1_000_000_000 / elapsed.as_nanos()) as u64
+}
+
+/// Return the TSC frequency in Hz, 0 before `init`
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Return the time `cycles` TSC cycles take
+pub fn cycles_to_duration(cycles: u64) -> Duration {
+    let frequency = frequency();
+    assert!(frequency != 0, "TSC not calibrated");
+    Duration::from_nanos((cycles as u128 * 1_000_000_000 / frequency as u128) as u64)
+}
+
+/// Return the number of TSC cycles in `duration`
+pub fn duration_to_cycles(duration: Duration) -> u64 {
+    (duration.as_nanos() * frequency() as u128 / 1_000_000_000) as u64
+}
+
+/// A point in time read from the TSC, for measuring short intervals
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(rdtsc())
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.0
+    }
+
+    /// Return the time from `earlier` to `self`, 0 if `earlier` is later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        cycles_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0 + duration_to_cycles(duration))
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        Instant(self.0 - duration_to_cycles(duration))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
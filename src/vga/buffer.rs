@@ -1,11 +1,10 @@
 use volatile::Volatile;
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
-use x86_64::instructions::interrupts::without_interrupts;
+use crate::sync::IrqMutex;
 
 lazy_static! {
-    pub static ref CONSOLE: Mutex<Console> = Mutex::new(Console {
+    pub static ref CONSOLE: IrqMutex<Console> = IrqMutex::new(Console {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
@@ -69,6 +68,18 @@ impl Console {
     pub fn write_char(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            // backspace, erases the previous character of the line
+            0x08 => {
+                if self.column_position > 0 {
+                    self.column_position -= 1;
+                    let col = self.column_position as usize;
+                    let color_code = self.color_code;
+                    self.buffer.chars[BUFFER_HEIGHT - 1][col].write(Char {
+                        ascii_character: b' ',
+                        color_code,
+                    });
+                }
+            }
             byte => {
                 if self.column_position >= BUFFER_WIDTH as u8 {
                     self.new_line();
@@ -90,7 +101,7 @@ impl Console {
     pub fn write_line(&mut self, s: &str) {
         for byte in s.bytes() {
             match byte {
-                0x20..=0x7e | b'\n' => self.write_char(byte),
+                0x20..=0x7e | b'\n' | 0x08 => self.write_char(byte),
                 _ => self.write_char(0xfe),
             }
         }
@@ -152,7 +163,5 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    without_interrupts(|| {
-        CONSOLE.lock().write_fmt(args).unwrap();
-    })
+    CONSOLE.lock().write_fmt(args).unwrap();
 }
\ No newline at end of file